@@ -1,4 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("../../proto/docs.proto")?;
+    tonic_build::compile_protos("../../proto/blueprints.proto")?;
+    tonic_build::compile_protos("../../proto/auth.proto")?;
     Ok(())
 }
@@ -0,0 +1,332 @@
+//! Login of the users of the `user` table, for setups without the legacy auth server.
+//! Passwords are checked against their argon2 or bcrypt hash and the issued tokens carry the same claims
+//! as the ones of the legacy server. The access and refresh tokens of a login share their `jti`,
+//! so revoking it on logout or refresh revokes both.
+use std::{fs, sync::Arc};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, SaltString},
+    Argon2, PasswordVerifier,
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::{
+    auth::{auth_server::Auth, LoginRequest, RefreshRequest, TokenResponse},
+    jwt_keys::{env_list, JWT_KEYS},
+    queries,
+    token_revocation::{token_max_lifetime, REVOKED_TOKENS},
+    utils::now_secs,
+    Claims,
+};
+
+/// Hash checked for unknown users, so that they take as long to reject as wrong passwords
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::encode_b64(b"doscenario-dummy").unwrap();
+    Argon2::default()
+        .hash_password(b"dummy", &salt)
+        .unwrap()
+        .to_string()
+});
+
+/// Claims of the issued tokens
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenClaims {
+    #[serde(flatten)]
+    claims: Claims,
+    exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+}
+
+struct TokenSigner {
+    header: Header,
+    key: EncodingKey,
+}
+
+#[derive(Clone)]
+pub struct AuthService {
+    // None when no signing key is configured, every call is then rejected
+    signer: Option<Arc<TokenSigner>>,
+    access_ttl: i64,
+    refresh_ttl: i64,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl AuthService {
+    /// Tokens are signed with the RS256 or EdDSA private key of the PEM file `JWT_SIGNING_KEY`,
+    /// with the `JWT_SIGNING_KID` key id, or with the HS256 secret `PRIVATE_KEY`.
+    /// The access tokens last `ACCESS_TOKEN_TTL` seconds, 15 minutes by default,
+    /// and the refresh tokens `REFRESH_TOKEN_TTL` seconds, 30 days by default.
+    /// Refresh tokens must not outlive `TOKEN_MAX_LIFETIME`, user revocations would expire before them
+    pub fn from_env() -> Result<Self, String> {
+        let signer = match TokenSigner::from_env()? {
+            Some(signer) => Some(Arc::new(signer)),
+            None => {
+                log::warn!("No JWT signing key configured, the auth service is disabled");
+                None
+            }
+        };
+        let refresh_ttl = env_seconds("REFRESH_TOKEN_TTL", 30 * 24 * 60 * 60)?;
+        check_refresh_ttl(refresh_ttl, token_max_lifetime())?;
+        Ok(Self {
+            signer,
+            access_ttl: env_seconds("ACCESS_TOKEN_TTL", 15 * 60)?,
+            refresh_ttl,
+            // The first accepted issuer and audience, so that the issued tokens pass `check_auth`
+            issuer: env_list("JWT_ISSUER").and_then(|v| v.into_iter().next()),
+            audience: env_list("JWT_AUDIENCE").and_then(|v| v.into_iter().next()),
+        })
+    }
+
+    /// Issue a new pair of tokens for a user
+    fn issue_tokens(&self, user_id: String) -> Result<TokenResponse, Status> {
+        let signer = self.signer()?;
+        let iat = now_secs();
+        let jti = Uuid::new_v4().to_string();
+        let sign = |refresh: bool, ttl: i64| {
+            let claims = TokenClaims {
+                claims: Claims {
+                    sub: user_id.clone(),
+                    jti: Some(jti.clone()),
+                    iat: Some(iat),
+                    refresh,
+                },
+                exp: iat + ttl,
+                iss: self.issuer.clone(),
+                aud: self.audience.clone(),
+            };
+            jsonwebtoken::encode(&signer.header, &claims, &signer.key)
+                .map_err(|e| Status::internal(format!("Cannot sign token: {}", e)))
+        };
+        Ok(TokenResponse {
+            access_token: sign(false, self.access_ttl)?,
+            refresh_token: sign(true, self.refresh_ttl)?,
+            user_id,
+            expires_in: self.access_ttl,
+        })
+    }
+
+    /// Verify a refresh token that is not revoked yet and return its claims
+    fn decode_refresh_token(&self, token: &str) -> Result<TokenClaims, Status> {
+        self.signer()?;
+        let token: TokenClaims = JWT_KEYS.get().unwrap().decode(token)?;
+        let Claims {
+            sub,
+            jti,
+            iat,
+            refresh,
+        } = &token.claims;
+        if !refresh || jti.is_none() {
+            return Err(Status::unauthenticated("Invalid refresh token"));
+        }
+        if REVOKED_TOKENS
+            .get()
+            .unwrap()
+            .is_revoked(sub, jti.as_deref(), *iat)
+        {
+            return Err(Status::unauthenticated("Refresh token has been revoked"));
+        }
+        Ok(token)
+    }
+
+    /// Revoke both tokens of a login, the revocation can be dropped when the refresh token expires
+    /// Return false if they were already revoked
+    async fn revoke(&self, token: &TokenClaims) -> Result<bool, Status> {
+        let jti = token.claims.jti.as_deref().unwrap_or_default();
+        REVOKED_TOKENS
+            .get()
+            .unwrap()
+            .revoke_token(jti, Some(token.exp))
+            .await
+    }
+
+    fn signer(&self) -> Result<&TokenSigner, Status> {
+        self.signer
+            .as_deref()
+            .ok_or_else(|| Status::unimplemented("No JWT signing key configured"))
+    }
+}
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    async fn login(
+        &self,
+        request: Request<LoginRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        let data = request.into_inner();
+        self.signer()?;
+        let user = queries::get_user_by_name(&data.name).await?;
+        let hash = match &user {
+            Some(user) => user.password.clone(),
+            None => DUMMY_HASH.clone(),
+        };
+        let valid = verify_password(data.password, hash).await?;
+        // Unknown users and wrong passwords get the same error
+        let user = match user {
+            Some(user) if valid => user,
+            _ => return Err(Status::unauthenticated("Invalid name or password")),
+        };
+        log::info!("User {} logged in", user.id);
+        Ok(Response::new(self.issue_tokens(user.id)?))
+    }
+
+    async fn refresh(
+        &self,
+        request: Request<RefreshRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        let data = request.into_inner();
+        let token = self.decode_refresh_token(&data.refresh_token)?;
+        // The user may have been removed since the login
+        let user = queries::get_user(&token.claims.sub).await?;
+        // Only one of concurrent refreshes of a token inserts its revocation
+        if !self.revoke(&token).await? {
+            return Err(Status::unauthenticated("Refresh token has been revoked"));
+        }
+        Ok(Response::new(self.issue_tokens(user.id)?))
+    }
+
+    async fn logout(&self, request: Request<RefreshRequest>) -> Result<Response<()>, Status> {
+        let data = request.into_inner();
+        let token = self.decode_refresh_token(&data.refresh_token)?;
+        self.revoke(&token).await?;
+        log::info!("User {} logged out", token.claims.sub);
+        Ok(Response::new(()))
+    }
+}
+
+impl TokenSigner {
+    fn from_env() -> Result<Option<Self>, String> {
+        if let Ok(path) = std::env::var("JWT_SIGNING_KEY") {
+            let pem = fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let (algorithm, key) = if let Ok(key) = EncodingKey::from_rsa_pem(&pem) {
+                (Algorithm::RS256, key)
+            } else if let Ok(key) = EncodingKey::from_ed_pem(&pem) {
+                (Algorithm::EdDSA, key)
+            } else {
+                return Err(format!("{} is not an RSA or Ed25519 private key", path));
+            };
+            let mut header = Header::new(algorithm);
+            header.kid = std::env::var("JWT_SIGNING_KID").ok();
+            return Ok(Some(Self { header, key }));
+        }
+        Ok(std::env::var("PRIVATE_KEY").ok().map(|secret| Self {
+            header: Header::new(Algorithm::HS256),
+            key: EncodingKey::from_secret(secret.as_bytes()),
+        }))
+    }
+}
+
+/// Check a password against an argon2 or bcrypt hash, off the async runtime as hashing is slow on purpose
+async fn verify_password(password: String, hash: String) -> Result<bool, Status> {
+    tokio::task::spawn_blocking(move || {
+        if hash.starts_with("$argon2") {
+            PasswordHash::new(&hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        } else if hash.starts_with("$2") {
+            bcrypt::verify(&password, &hash).unwrap_or(false)
+        } else {
+            log::warn!("Unsupported password hash format");
+            false
+        }
+    })
+    .await
+    .map_err(|e| Status::internal(e.to_string()))
+}
+
+fn check_refresh_ttl(refresh_ttl: i64, max_lifetime: i64) -> Result<(), String> {
+    if refresh_ttl > max_lifetime {
+        return Err(format!(
+            "REFRESH_TOKEN_TTL ({}) exceeds TOKEN_MAX_LIFETIME ({})",
+            refresh_ttl, max_lifetime
+        ));
+    }
+    Ok(())
+}
+
+fn env_seconds(name: &str, default: i64) -> Result<i64, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid {}: {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jwt_keys::JwtKeys, token_revocation::RevokedTokens};
+
+    const SECRET: &str = "auth-service-test-secret";
+
+    fn service() -> AuthService {
+        JWT_KEYS.get_or_init(|| {
+            std::env::set_var("PRIVATE_KEY", SECRET);
+            JwtKeys::from_env().unwrap()
+        });
+        REVOKED_TOKENS.get_or_init(RevokedTokens::from_env);
+        AuthService {
+            signer: Some(Arc::new(TokenSigner {
+                header: Header::new(Algorithm::HS256),
+                key: EncodingKey::from_secret(SECRET.as_bytes()),
+            })),
+            access_ttl: 60,
+            refresh_ttl: 120,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    #[test]
+    fn login_tokens_share_their_jti() {
+        let service = service();
+        let tokens = service.issue_tokens("user".to_string()).unwrap();
+        let access: TokenClaims = JWT_KEYS
+            .get()
+            .unwrap()
+            .decode(&tokens.access_token)
+            .unwrap();
+        let refresh = service.decode_refresh_token(&tokens.refresh_token).unwrap();
+        assert_eq!(refresh.claims.sub, "user");
+        assert!(!access.claims.refresh);
+        assert!(access.claims.jti.is_some());
+        assert_eq!(access.claims.jti, refresh.claims.jti);
+        assert_eq!(refresh.exp - access.exp, 60);
+    }
+
+    #[test]
+    fn access_tokens_cannot_be_refreshed() {
+        let service = service();
+        let tokens = service.issue_tokens("user".to_string()).unwrap();
+        assert!(service.decode_refresh_token(&tokens.access_token).is_err());
+        assert!(service.decode_refresh_token("not a token").is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_checked_against_a_working_hash() {
+        assert!(verify_password("dummy".to_string(), DUMMY_HASH.clone())
+            .await
+            .unwrap());
+        assert!(!verify_password("password".to_string(), DUMMY_HASH.clone())
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn refresh_tokens_must_not_outlive_user_revocations() {
+        assert!(check_refresh_ttl(100, 100).is_ok());
+        assert!(check_refresh_ttl(101, 100).is_err());
+    }
+}
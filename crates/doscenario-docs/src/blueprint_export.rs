@@ -0,0 +1,409 @@
+//! Export of a blueprint to Graphviz DOT, to a self-contained SVG drawn at the stored node positions
+//! and to the JSON format of `blueprint_json`
+use std::{collections::HashMap, fmt::Write};
+
+use doscenario_models::{
+    blueprint::BlueprintModel,
+    node::NodeModel,
+    relationship::RelationshipModel,
+    sea_orm_active_enums::{ChildPole, ParentPole, Type},
+    tag::TagModel,
+};
+
+use crate::{blueprint_graph::BlueprintGraph, blueprint_json::BlueprintJson};
+
+/// Size of a node drawn in the SVG, its position is its top left corner
+const NODE_WIDTH: f64 = 200.0;
+const NODE_HEIGHT: f64 = 100.0;
+const MARGIN: f64 = 40.0;
+const TITLE_HEIGHT: f64 = 40.0;
+/// Characters per line and lines of the summary drawn in a node
+const LINE_LENGTH: usize = 28;
+const MAX_LINES: usize = 4;
+const DEFAULT_COLOR: &str = "#ffffff";
+
+/// Blueprint with everything needed to draw it
+pub struct BlueprintExport<'a> {
+    pub blueprint: &'a BlueprintModel,
+    pub graph: &'a BlueprintGraph,
+    // Map a node id to its tags
+    pub tags: HashMap<i32, Vec<&'a TagModel>>,
+}
+
+impl BlueprintExport<'_> {
+    /// Render the blueprint as a Graphviz directed graph
+    /// Node positions are kept as pinned `pos` attributes for the neato engine
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        let title = dot_escape(&self.blueprint.title);
+        writeln!(out, "digraph \"{title}\" {{").unwrap();
+        writeln!(out, "  graph [label=\"{title}\", labelloc=t];").unwrap();
+        writeln!(
+            out,
+            "  node [shape=box, style=\"rounded,filled\", fillcolor=\"{DEFAULT_COLOR}\"];"
+        )
+        .unwrap();
+        for node in sorted_nodes(self.graph) {
+            let mut label = node_label(node);
+            let tags = self.tag_titles(node.id);
+            if !tags.is_empty() {
+                label.push('\n');
+                label.push_str(&tags.join(" "));
+            }
+            // Graphviz points have the y axis going up
+            write!(
+                out,
+                "  n{} [label=\"{}\", fillcolor=\"{}\", pos=\"{},{}!\"",
+                node.id,
+                dot_escape(&label),
+                color(&node.color),
+                node.x,
+                -node.y
+            )
+            .unwrap();
+            if node.is_root != 0 {
+                out.push_str(", peripheries=2");
+            }
+            out.push_str("];\n");
+        }
+        for rel in sorted_relationships(self.graph) {
+            write!(
+                out,
+                "  n{} -> n{} [tailport={}, headport={}",
+                rel.parent_id,
+                rel.child_id,
+                parent_pole_name(&rel.parent_pole),
+                child_pole_name(&rel.child_pole)
+            )
+            .unwrap();
+            if rel.r#type == Type::Loopback {
+                out.push_str(", style=dashed, constraint=false");
+            }
+            out.push_str("];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Render the blueprint as an SVG document with the stored node positions
+    pub fn to_svg(&self) -> String {
+        let nodes = sorted_nodes(self.graph);
+        let min_x = nodes
+            .iter()
+            .map(|n| n.x as f64)
+            .fold(f64::INFINITY, f64::min);
+        let min_y = nodes
+            .iter()
+            .map(|n| n.y as f64)
+            .fold(f64::INFINITY, f64::min);
+        let max_x = nodes
+            .iter()
+            .map(|n| n.x as f64)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let max_y = nodes
+            .iter()
+            .map(|n| n.y as f64)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let (min_x, min_y, max_x, max_y) = if nodes.is_empty() {
+            (0.0, 0.0, 0.0, 0.0)
+        } else {
+            (min_x, min_y, max_x + NODE_WIDTH, max_y + NODE_HEIGHT)
+        };
+        // Every coordinate is shifted so that the drawing starts at the margin below the title
+        let offset_x = MARGIN - min_x;
+        let offset_y = MARGIN + TITLE_HEIGHT - min_y;
+        let width = max_x - min_x + 2.0 * MARGIN;
+        let height = max_y - min_y + 2.0 * MARGIN + TITLE_HEIGHT;
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="sans-serif">"#
+        )
+        .unwrap();
+        out.push_str(concat!(
+            r#"<defs><marker id="arrow" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="8" markerHeight="8" orient="auto-start-reverse">"#,
+            r##"<path d="M 0 0 L 10 5 L 0 10 z" fill="#555"/></marker></defs>"##,
+            "\n"
+        ));
+        writeln!(
+            out,
+            r#"<rect width="100%" height="100%" fill="white"/><text x="{MARGIN}" y="{}" font-size="20" font-weight="bold">{}</text>"#,
+            MARGIN,
+            xml_escape(&self.blueprint.title)
+        )
+        .unwrap();
+
+        let positions: HashMap<i32, (f64, f64)> = nodes
+            .iter()
+            .map(|n| (n.id, (n.x as f64 + offset_x, n.y as f64 + offset_y)))
+            .collect();
+        for rel in sorted_relationships(self.graph) {
+            let (Some(parent), Some(child)) =
+                (positions.get(&rel.parent_id), positions.get(&rel.child_id))
+            else {
+                continue;
+            };
+            let (x1, y1, dx1, dy1) =
+                anchor(*parent, pole_offset(parent_pole_name(&rel.parent_pole)));
+            let (x2, y2, dx2, dy2) = anchor(*child, pole_offset(child_pole_name(&rel.child_pole)));
+            let dash = if rel.r#type == Type::Loopback {
+                r#" stroke-dasharray="6 4""#
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                r##"<path d="M {x1} {y1} C {} {} {} {} {x2} {y2}" fill="none" stroke="#555" stroke-width="2"{dash} marker-end="url(#arrow)"/>"##,
+                x1 + dx1 * 60.0,
+                y1 + dy1 * 60.0,
+                x2 + dx2 * 60.0,
+                y2 + dy2 * 60.0,
+            )
+            .unwrap();
+        }
+
+        for node in nodes {
+            let (x, y) = positions[&node.id];
+            let stroke_width = if node.is_root != 0 { 3 } else { 1 };
+            writeln!(
+                out,
+                r##"<g><rect x="{x}" y="{y}" width="{NODE_WIDTH}" height="{NODE_HEIGHT}" rx="8" fill="{}" stroke="#333" stroke-width="{stroke_width}"/>"##,
+                color(&node.color)
+            )
+            .unwrap();
+            for (i, line) in wrap(&node_label(node)).iter().enumerate() {
+                writeln!(
+                    out,
+                    r#"<text x="{}" y="{}" font-size="13">{}</text>"#,
+                    x + 10.0,
+                    y + 22.0 + i as f64 * 16.0,
+                    xml_escape(line)
+                )
+                .unwrap();
+            }
+            let tags = self.tag_titles(node.id);
+            if !tags.is_empty() {
+                writeln!(
+                    out,
+                    r##"<text x="{}" y="{}" font-size="11" fill="#555">{}</text>"##,
+                    x + 10.0,
+                    y + NODE_HEIGHT - 8.0,
+                    xml_escape(&tags.join(" "))
+                )
+                .unwrap();
+            }
+            out.push_str("</g>\n");
+        }
+        out.push_str("</svg>\n");
+        out
+    }
+
+    /// Serialize the blueprint to the versioned JSON format that can be imported back
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&BlueprintJson::from_export(self)).unwrap()
+    }
+
+    fn tag_titles(&self, node_id: i32) -> Vec<String> {
+        self.tags
+            .get(&node_id)
+            .map(|tags| tags.iter().map(|t| format!("#{}", t.title)).collect())
+            .unwrap_or_default()
+    }
+}
+
+fn sorted_nodes(graph: &BlueprintGraph) -> Vec<&NodeModel> {
+    let mut nodes: Vec<&NodeModel> = graph.nodes.iter().collect();
+    nodes.sort_by_key(|n| n.id);
+    nodes
+}
+
+fn sorted_relationships(graph: &BlueprintGraph) -> Vec<&RelationshipModel> {
+    let mut relationships: Vec<&RelationshipModel> = graph.relationships.iter().collect();
+    relationships.sort_by_key(|r| r.id);
+    relationships
+}
+
+/// Text shown in a node, its summary or its id if it has none
+fn node_label(node: &NodeModel) -> String {
+    match node.summary.as_deref().map(str::trim) {
+        Some(summary) if !summary.is_empty() => summary.to_string(),
+        _ => format!("Node {}", node.id),
+    }
+}
+
+/// Wrap a text on words to fit in a node, the last line ends with an ellipsis if it is cut
+fn wrap(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + word.chars().count() >= LINE_LENGTH {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        lines[MAX_LINES - 1].push('…');
+    }
+    lines
+}
+
+/// Keep only colors that can't break out of an attribute
+fn color(color: &Option<String>) -> &str {
+    match color.as_deref() {
+        Some(c) if !c.is_empty() && c.chars().all(|c| c.is_ascii_alphanumeric() || c == '#') => c,
+        _ => DEFAULT_COLOR,
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn parent_pole_name(pole: &ParentPole) -> &'static str {
+    match pole {
+        ParentPole::N => "n",
+        ParentPole::S => "s",
+        ParentPole::E => "e",
+        ParentPole::W => "w",
+    }
+}
+
+fn child_pole_name(pole: &ChildPole) -> &'static str {
+    match pole {
+        ChildPole::N => "n",
+        ChildPole::S => "s",
+        ChildPole::E => "e",
+        ChildPole::W => "w",
+    }
+}
+
+/// Unit vector pointing out of the node side of a pole
+fn pole_offset(pole: &str) -> (f64, f64) {
+    match pole {
+        "n" => (0.0, -1.0),
+        "s" => (0.0, 1.0),
+        "e" => (1.0, 0.0),
+        _ => (-1.0, 0.0),
+    }
+}
+
+/// Point in the middle of a node side and the direction going out of it
+fn anchor((x, y): (f64, f64), (dx, dy): (f64, f64)) -> (f64, f64, f64, f64) {
+    (
+        x + NODE_WIDTH / 2.0 * (1.0 + dx),
+        y + NODE_HEIGHT / 2.0 * (1.0 + dy),
+        dx,
+        dy,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blueprint_graph::tests::{direct, node, relationship};
+    use sqlx::types::time::PrimitiveDateTime;
+
+    fn blueprint(title: &str) -> BlueprintModel {
+        BlueprintModel {
+            id: 1,
+            project_id: 1,
+            created_by_id: None,
+            last_editor_id: None,
+            created_date: PrimitiveDateTime::MIN,
+            last_editing: PrimitiveDateTime::MIN,
+            uid: "uid".to_string(),
+            color: None,
+            title: title.to_string(),
+        }
+    }
+
+    fn tag(title: &str) -> TagModel {
+        TagModel {
+            id: 1,
+            title: title.to_string(),
+            primary: 0,
+            color: None,
+            project_id: Some(1),
+            created_by_id: None,
+        }
+    }
+
+    fn graph() -> BlueprintGraph {
+        let mut start = node(1, true);
+        start.summary = Some(" Say \"hi\" \\ <b>now</b> ".to_string());
+        let mut end = node(2, false);
+        end.x = 300;
+        end.y = 200;
+        end.color = Some("red\" onload=\"x".to_string());
+        BlueprintGraph::new(
+            vec![end, start],
+            vec![
+                relationship(2, 1, ParentPole::E, ChildPole::W, Type::Loopback),
+                direct(1, 2),
+            ],
+        )
+    }
+
+    #[test]
+    fn dot_strings_are_escaped() {
+        assert_eq!(dot_escape("a \"b\" \\c\nd"), "a \\\"b\\\" \\\\c\\nd");
+    }
+
+    #[test]
+    fn dot_export_is_sorted_and_escaped() {
+        let blueprint = blueprint("The \"End\"");
+        let graph = graph();
+        let tag = tag("act \"1\"");
+        let export = BlueprintExport {
+            blueprint: &blueprint,
+            graph: &graph,
+            tags: HashMap::from([(2, vec![&tag])]),
+        };
+        assert_eq!(
+            export.to_dot(),
+            concat!(
+                "digraph \"The \\\"End\\\"\" {\n",
+                "  graph [label=\"The \\\"End\\\"\", labelloc=t];\n",
+                "  node [shape=box, style=\"rounded,filled\", fillcolor=\"#ffffff\"];\n",
+                "  n1 [label=\"Say \\\"hi\\\" \\\\ <b>now</b>\", fillcolor=\"#ffffff\", pos=\"0,0!\", peripheries=2];\n",
+                "  n2 [label=\"Node 2\\n#act \\\"1\\\"\", fillcolor=\"#ffffff\", pos=\"300,-200!\"];\n",
+                "  n1 -> n2 [tailport=s, headport=n];\n",
+                "  n2 -> n1 [tailport=e, headport=w, style=dashed, constraint=false];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn svg_text_and_colors_are_escaped() {
+        let blueprint = blueprint("<Story> & \"co\"");
+        let graph = graph();
+        let export = BlueprintExport {
+            blueprint: &blueprint,
+            graph: &graph,
+            tags: HashMap::new(),
+        };
+        let svg = export.to_svg();
+        assert!(svg.contains("&lt;Story&gt; &amp; &quot;co&quot;"));
+        assert!(svg.contains("&lt;b&gt;now&lt;/b&gt;"));
+        assert!(!svg.contains("onload"));
+        assert!(!svg.contains("<b>"));
+    }
+}
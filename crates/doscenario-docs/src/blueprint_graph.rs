@@ -0,0 +1,416 @@
+//! Story graph of a blueprint built from its nodes and relationships.
+//! `Direct` relationships must form an acyclic graph under a single root node,
+//! cycles are only allowed through `Loopback` relationships.
+use std::collections::{HashMap, HashSet};
+
+use doscenario_models::{
+    node::NodeModel, relationship::RelationshipModel, sea_orm_active_enums::Type,
+};
+use tonic::Status;
+
+/// Maximum number of paths returned by `paths_to`
+const MAX_PATHS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct BlueprintGraph {
+    pub nodes: Vec<NodeModel>,
+    pub relationships: Vec<RelationshipModel>,
+    // Map a node id to the children of its direct relationships
+    direct_children: HashMap<i32, Vec<i32>>,
+    // Map a node id to the parents of its direct relationships
+    direct_parents: HashMap<i32, Vec<i32>>,
+}
+
+impl BlueprintGraph {
+    pub fn new(nodes: Vec<NodeModel>, relationships: Vec<RelationshipModel>) -> Self {
+        let mut direct_children: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut direct_parents: HashMap<i32, Vec<i32>> = HashMap::new();
+        for rel in relationships.iter().filter(|r| r.r#type == Type::Direct) {
+            direct_children
+                .entry(rel.parent_id)
+                .or_default()
+                .push(rel.child_id);
+            direct_parents
+                .entry(rel.child_id)
+                .or_default()
+                .push(rel.parent_id);
+        }
+        Self {
+            nodes,
+            relationships,
+            direct_children,
+            direct_parents,
+        }
+    }
+
+    pub fn node(&self, id: i32) -> Option<&NodeModel> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+
+    /// Get the root node, a blueprint must have exactly one
+    pub fn root(&self) -> Result<&NodeModel, Status> {
+        let mut roots = self.nodes.iter().filter(|n| n.is_root != 0);
+        match (roots.next(), roots.next()) {
+            (Some(root), None) => Ok(root),
+            (None, _) => Err(Status::failed_precondition("Blueprint has no root node")),
+            (Some(_), Some(_)) => Err(Status::failed_precondition(
+                "Blueprint has more than one root node",
+            )),
+        }
+    }
+
+    /// Children of a node through direct relationships
+    pub fn direct_children(&self, id: i32) -> &[i32] {
+        self.direct_children
+            .get(&id)
+            .map(|c| c.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Parents of a node through direct relationships
+    pub fn direct_parents(&self, id: i32) -> &[i32] {
+        self.direct_parents
+            .get(&id)
+            .map(|c| c.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Check if `to` can be reached from `from` through direct relationships
+    pub fn reaches(&self, from: i32, to: i32) -> bool {
+        visit([from], |id| self.direct_children(id).to_vec()).contains(&to)
+    }
+
+    /// Every node reachable from a node through direct relationships, without the node itself
+    pub fn descendants(&self, id: i32) -> Vec<i32> {
+        sorted_without(visit([id], |id| self.direct_children(id).to_vec()), id)
+    }
+
+    /// Every node from which a node can be reached through direct relationships, without the node itself
+    pub fn ancestors(&self, id: i32) -> Vec<i32> {
+        sorted_without(visit([id], |id| self.direct_parents(id).to_vec()), id)
+    }
+
+    /// Every path of direct relationships from the root to a node
+    /// Return the paths and whether they were truncated to `MAX_PATHS`
+    pub fn paths_to(&self, id: i32) -> Result<(Vec<Vec<i32>>, bool), Status> {
+        let root = self.root()?.id;
+        // Only nodes that lead to the target are explored so that every branch gives a path
+        let leads_to_target = visit([id], |id| self.direct_parents(id).to_vec());
+        let mut paths = Vec::new();
+        if !leads_to_target.contains(&root) {
+            return Ok((paths, false));
+        }
+        let mut stack = vec![vec![root]];
+        while let Some(path) = stack.pop() {
+            let last = *path.last().unwrap();
+            if last == id {
+                if paths.len() == MAX_PATHS {
+                    return Ok((paths, true));
+                }
+                paths.push(path);
+                continue;
+            }
+            for child in self.direct_children(last).iter().rev() {
+                if leads_to_target.contains(child) && !path.contains(child) {
+                    let mut next = path.clone();
+                    next.push(*child);
+                    stack.push(next);
+                }
+            }
+        }
+        Ok((paths, false))
+    }
+
+    /// Nodes that can't be reached from the root through direct relationships
+    pub fn orphans(&self) -> Result<Vec<i32>, Status> {
+        let reachable = visit([self.root()?.id], |id| self.direct_children(id).to_vec());
+        let mut orphans: Vec<i32> = self
+            .nodes
+            .iter()
+            .map(|n| n.id)
+            .filter(|id| !reachable.contains(id))
+            .collect();
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    /// Nodes reachable from the root from which no ending can be reached
+    /// An ending is a node without any relationship to a child, loopbacks are followed
+    pub fn dead_ends(&self) -> Result<Vec<i32>, Status> {
+        let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut parents: HashMap<i32, Vec<i32>> = HashMap::new();
+        for rel in self.relationships.iter() {
+            children
+                .entry(rel.parent_id)
+                .or_default()
+                .push(rel.child_id);
+            parents.entry(rel.child_id).or_default().push(rel.parent_id);
+        }
+        let reachable = visit([self.root()?.id], |id| {
+            children.get(&id).cloned().unwrap_or_default()
+        });
+        let endings = self
+            .nodes
+            .iter()
+            .map(|n| n.id)
+            .filter(|id| !children.contains_key(id));
+        let reaches_ending = visit(endings, |id| parents.get(&id).cloned().unwrap_or_default());
+        let mut dead_ends: Vec<i32> = reachable
+            .into_iter()
+            .filter(|id| !reaches_ending.contains(id))
+            .collect();
+        dead_ends.sort();
+        Ok(dead_ends)
+    }
+
+    /// Check the whole graph as if each relationship was created in turn
+    pub fn validate(&self) -> Result<(), Status> {
+        self.root()?;
+        for rel in self.relationships.iter() {
+            self.validate_relationship(rel.parent_id, rel.child_id, &rel.r#type)
+                .map_err(|e| {
+                    Status::new(
+                        e.code(),
+                        format!("Relationship {}: {}", rel.id, e.message()),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Check that a new relationship keeps the graph valid
+    pub fn validate_relationship(
+        &self,
+        parent_id: i32,
+        child_id: i32,
+        r#type: &Type,
+    ) -> Result<(), Status> {
+        if self.node(parent_id).is_none() || self.node(child_id).is_none() {
+            return Err(Status::invalid_argument(
+                "Parent and child must be nodes of the blueprint",
+            ));
+        }
+        let root = self.root()?;
+        if *r#type == Type::Loopback {
+            return Ok(());
+        }
+        if child_id == root.id {
+            return Err(Status::invalid_argument(
+                "The root node cannot be the child of a direct relationship",
+            ));
+        }
+        if self.reaches(child_id, parent_id) {
+            return Err(Status::invalid_argument(
+                "Direct relationship would create a cycle, use a loopback relationship",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Every node reachable from the `from` nodes with the given neighbours, including them
+fn visit(
+    from: impl IntoIterator<Item = i32>,
+    neighbours: impl Fn(i32) -> Vec<i32>,
+) -> HashSet<i32> {
+    let mut visited = HashSet::new();
+    let mut stack: Vec<i32> = from.into_iter().collect();
+    while let Some(id) = stack.pop() {
+        if visited.insert(id) {
+            stack.extend(neighbours(id));
+        }
+    }
+    visited
+}
+
+fn sorted_without(ids: HashSet<i32>, id: i32) -> Vec<i32> {
+    let mut ids: Vec<i32> = ids.into_iter().filter(|i| *i != id).collect();
+    ids.sort();
+    ids
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use doscenario_models::sea_orm_active_enums::{ChildPole, ParentPole};
+    use sqlx::types::time::PrimitiveDateTime;
+    use tonic::Code;
+
+    pub(crate) fn node(id: i32, is_root: bool) -> NodeModel {
+        NodeModel {
+            id,
+            is_root: is_root as i8,
+            content: None,
+            blueprint_id: Some(1),
+            created_by_id: None,
+            last_editor_id: None,
+            x: 0,
+            y: 0,
+            summary: None,
+            created_date: PrimitiveDateTime::MIN,
+            last_editing: PrimitiveDateTime::MIN,
+            color: None,
+        }
+    }
+
+    pub(crate) fn relationship(
+        parent_id: i32,
+        child_id: i32,
+        parent_pole: ParentPole,
+        child_pole: ChildPole,
+        r#type: Type,
+    ) -> RelationshipModel {
+        RelationshipModel {
+            id: parent_id * 100 + child_id,
+            parent_id,
+            child_id,
+            blueprint_id: Some(1),
+            parent_pole,
+            child_pole,
+            r#type,
+        }
+    }
+
+    pub(crate) fn direct(parent_id: i32, child_id: i32) -> RelationshipModel {
+        relationship(
+            parent_id,
+            child_id,
+            ParentPole::S,
+            ChildPole::N,
+            Type::Direct,
+        )
+    }
+
+    /// Node 1 is the root of 1 -> 2 -> 3 and 1 -> 4, node 5 is not connected
+    fn graph() -> BlueprintGraph {
+        BlueprintGraph::new(
+            vec![
+                node(1, true),
+                node(2, false),
+                node(3, false),
+                node(4, false),
+                node(5, false),
+            ],
+            vec![direct(1, 2), direct(2, 3), direct(1, 4)],
+        )
+    }
+
+    #[test]
+    fn blueprints_need_a_single_root() {
+        assert_eq!(graph().root().unwrap().id, 1);
+        let none = BlueprintGraph::new(vec![node(1, false)], vec![]);
+        assert_eq!(none.root().unwrap_err().code(), Code::FailedPrecondition);
+        let two = BlueprintGraph::new(vec![node(1, true), node(2, true)], vec![]);
+        assert_eq!(two.root().unwrap_err().code(), Code::FailedPrecondition);
+        assert_eq!(
+            two.validate_relationship(1, 2, &Type::Direct)
+                .unwrap_err()
+                .code(),
+            Code::FailedPrecondition
+        );
+    }
+
+    #[test]
+    fn direct_relationships_cannot_create_cycles() {
+        let graph = graph();
+        assert!(graph.validate_relationship(4, 3, &Type::Direct).is_ok());
+        assert!(graph.validate_relationship(5, 2, &Type::Direct).is_ok());
+        let invalid = |parent, child, r#type| {
+            graph
+                .validate_relationship(parent, child, &r#type)
+                .unwrap_err()
+                .code()
+        };
+        assert_eq!(invalid(3, 1, Type::Direct), Code::InvalidArgument);
+        assert_eq!(invalid(3, 2, Type::Direct), Code::InvalidArgument);
+        assert_eq!(invalid(2, 2, Type::Direct), Code::InvalidArgument);
+        assert_eq!(invalid(4, 1, Type::Direct), Code::InvalidArgument);
+        assert_eq!(invalid(1, 6, Type::Direct), Code::InvalidArgument);
+        // Loopbacks may go back up, even to the root
+        assert!(graph.validate_relationship(3, 2, &Type::Loopback).is_ok());
+        assert!(graph.validate_relationship(3, 1, &Type::Loopback).is_ok());
+        assert_eq!(invalid(3, 6, Type::Loopback), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn descendants_and_ancestors_follow_direct_relationships() {
+        let graph = BlueprintGraph::new(
+            graph().nodes,
+            vec![
+                direct(1, 2),
+                direct(2, 3),
+                direct(1, 4),
+                relationship(3, 1, ParentPole::S, ChildPole::N, Type::Loopback),
+            ],
+        );
+        assert_eq!(graph.descendants(1), vec![2, 3, 4]);
+        assert_eq!(graph.descendants(2), vec![3]);
+        assert_eq!(graph.descendants(3), Vec::<i32>::new());
+        assert_eq!(graph.ancestors(3), vec![1, 2]);
+        assert_eq!(graph.ancestors(1), Vec::<i32>::new());
+        assert_eq!(graph.ancestors(5), Vec::<i32>::new());
+        assert!(graph.reaches(1, 3));
+        assert!(!graph.reaches(3, 1));
+    }
+
+    #[test]
+    fn paths_go_from_the_root_to_the_node() {
+        // 1 -> 2 -> 4 and 1 -> 3 -> 4, then 4 -> 5
+        let graph = BlueprintGraph::new(
+            (1..=6).map(|id| node(id, id == 1)).collect(),
+            vec![
+                direct(1, 2),
+                direct(1, 3),
+                direct(2, 4),
+                direct(3, 4),
+                direct(4, 5),
+            ],
+        );
+        assert_eq!(
+            graph.paths_to(5).unwrap(),
+            (vec![vec![1, 2, 4, 5], vec![1, 3, 4, 5]], false)
+        );
+        assert_eq!(graph.paths_to(2).unwrap(), (vec![vec![1, 2]], false));
+        assert_eq!(graph.paths_to(1).unwrap(), (vec![vec![1]], false));
+        assert_eq!(graph.paths_to(6).unwrap(), (vec![], false));
+    }
+
+    #[test]
+    fn paths_are_truncated() {
+        // Each of the 10 diamonds doubles the number of paths to the last node
+        let mut relationships = Vec::new();
+        for i in 0..10 {
+            let top = 1 + i * 3;
+            relationships.extend([
+                direct(top, top + 1),
+                direct(top, top + 2),
+                direct(top + 1, top + 3),
+                direct(top + 2, top + 3),
+            ]);
+        }
+        let graph = BlueprintGraph::new(
+            (1..=31).map(|id| node(id, id == 1)).collect(),
+            relationships,
+        );
+        let (paths, truncated) = graph.paths_to(31).unwrap();
+        assert!(truncated);
+        assert_eq!(paths.len(), MAX_PATHS);
+    }
+
+    #[test]
+    fn orphans_and_dead_ends() {
+        assert_eq!(graph().orphans().unwrap(), vec![5]);
+        // 3 and 4 loop on each other and never reach the ending 2
+        let graph = BlueprintGraph::new(
+            (1..=5).map(|id| node(id, id == 1)).collect(),
+            vec![
+                direct(1, 2),
+                direct(1, 3),
+                direct(3, 4),
+                relationship(4, 3, ParentPole::S, ChildPole::N, Type::Loopback),
+            ],
+        );
+        assert_eq!(graph.orphans().unwrap(), vec![5]);
+        assert_eq!(graph.dead_ends().unwrap(), vec![3, 4]);
+    }
+}
@@ -0,0 +1,413 @@
+//! Versioned JSON format of a whole blueprint, used to move blueprints between projects.
+//! Node and relationship ids are only references inside the file, they are remapped on import.
+use std::collections::HashSet;
+
+use doscenario_models::{
+    node::NodeModel,
+    relationship::RelationshipModel,
+    sea_orm_active_enums::{ChildPole, ParentPole, Type},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::time::PrimitiveDateTime;
+use tonic::Status;
+
+use crate::{blueprint_export::BlueprintExport, blueprint_graph::BlueprintGraph};
+
+/// Version written in exported files, files with another version are rejected
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlueprintJson {
+    pub version: u32,
+    pub title: String,
+    pub color: Option<String>,
+    pub nodes: Vec<NodeJson>,
+    pub relationships: Vec<RelationshipJson>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeJson {
+    pub id: i32,
+    pub is_root: bool,
+    pub content: Option<String>,
+    pub summary: Option<String>,
+    pub x: i32,
+    pub y: i32,
+    pub color: Option<String>,
+    // Tags are matched by title with the tags of the target project
+    #[serde(default)]
+    pub tags: Vec<TagJson>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagJson {
+    pub title: String,
+    pub color: Option<String>,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelationshipJson {
+    pub id: i32,
+    pub parent_id: i32,
+    pub child_id: i32,
+    pub parent_pole: PoleJson,
+    pub child_pole: PoleJson,
+    pub r#type: TypeJson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PoleJson {
+    N,
+    S,
+    E,
+    W,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypeJson {
+    Direct,
+    Loopback,
+}
+
+impl BlueprintJson {
+    pub fn from_export(export: &BlueprintExport) -> Self {
+        let mut nodes: Vec<NodeJson> = export
+            .graph
+            .nodes
+            .iter()
+            .map(|node| NodeJson {
+                id: node.id,
+                is_root: node.is_root != 0,
+                content: node.content.clone(),
+                summary: node.summary.clone(),
+                x: node.x,
+                y: node.y,
+                color: node.color.clone(),
+                tags: export
+                    .tags
+                    .get(&node.id)
+                    .into_iter()
+                    .flatten()
+                    .map(|tag| TagJson {
+                        title: tag.title.clone(),
+                        color: tag.color.clone(),
+                        primary: tag.primary != 0,
+                    })
+                    .collect(),
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.id);
+        let mut relationships: Vec<RelationshipJson> = export
+            .graph
+            .relationships
+            .iter()
+            .map(|rel| RelationshipJson {
+                id: rel.id,
+                parent_id: rel.parent_id,
+                child_id: rel.child_id,
+                parent_pole: (&rel.parent_pole).into(),
+                child_pole: (&rel.child_pole).into(),
+                r#type: (&rel.r#type).into(),
+            })
+            .collect();
+        relationships.sort_by_key(|r| r.id);
+        Self {
+            version: FORMAT_VERSION,
+            title: export.blueprint.title.clone(),
+            color: export.blueprint.color.clone(),
+            nodes,
+            relationships,
+        }
+    }
+
+    /// Parse and validate an exported blueprint
+    pub fn parse(content: &str) -> Result<Self, Status> {
+        // The version is checked first so that files of other versions get a clear error
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let Versioned { version } = serde_json::from_str(content)
+            .map_err(|e| Status::invalid_argument(format!("Invalid blueprint file: {}", e)))?;
+        if version != FORMAT_VERSION {
+            return Err(Status::invalid_argument(format!(
+                "Unsupported blueprint file version {}, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let blueprint: Self = serde_json::from_str(content)
+            .map_err(|e| Status::invalid_argument(format!("Invalid blueprint file: {}", e)))?;
+        blueprint.validate()?;
+        Ok(blueprint)
+    }
+
+    /// Check that the blueprint can be imported with the constraints of created relationships:
+    /// a single root, relationships between nodes of the blueprint and no cycle of direct relationships
+    pub fn validate(&self) -> Result<(), Status> {
+        if self.version != FORMAT_VERSION {
+            return Err(Status::invalid_argument(format!(
+                "Unsupported blueprint file version {}, expected {}",
+                self.version, FORMAT_VERSION
+            )));
+        }
+        if self.title.trim().is_empty() {
+            return Err(Status::invalid_argument("Blueprint title is empty"));
+        }
+        let mut node_ids = HashSet::new();
+        for node in self.nodes.iter() {
+            if !node_ids.insert(node.id) {
+                return Err(Status::invalid_argument(format!(
+                    "Duplicate node id {}",
+                    node.id
+                )));
+            }
+        }
+        let mut relationship_ids = HashSet::new();
+        for rel in self.relationships.iter() {
+            if !relationship_ids.insert(rel.id) {
+                return Err(Status::invalid_argument(format!(
+                    "Duplicate relationship id {}",
+                    rel.id
+                )));
+            }
+        }
+        // Relationships of the file must pass the checks of the ones created in a blueprint
+        self.to_graph()
+            .validate()
+            .map_err(|e| Status::invalid_argument(e.message()))
+    }
+
+    /// Story graph of the file, nodes get the oldest date as dates are not part of the format
+    fn to_graph(&self) -> BlueprintGraph {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| NodeModel {
+                id: node.id,
+                is_root: node.is_root as i8,
+                content: node.content.clone(),
+                blueprint_id: None,
+                created_by_id: None,
+                last_editor_id: None,
+                x: node.x,
+                y: node.y,
+                summary: node.summary.clone(),
+                created_date: PrimitiveDateTime::MIN,
+                last_editing: PrimitiveDateTime::MIN,
+                color: node.color.clone(),
+            })
+            .collect();
+        let relationships = self
+            .relationships
+            .iter()
+            .map(|rel| RelationshipModel {
+                id: rel.id,
+                parent_id: rel.parent_id,
+                child_id: rel.child_id,
+                blueprint_id: None,
+                parent_pole: rel.parent_pole.into(),
+                child_pole: rel.child_pole.into(),
+                r#type: rel.r#type.into(),
+            })
+            .collect();
+        BlueprintGraph::new(nodes, relationships)
+    }
+}
+
+impl From<&ParentPole> for PoleJson {
+    fn from(pole: &ParentPole) -> Self {
+        match pole {
+            ParentPole::N => PoleJson::N,
+            ParentPole::S => PoleJson::S,
+            ParentPole::E => PoleJson::E,
+            ParentPole::W => PoleJson::W,
+        }
+    }
+}
+impl From<&ChildPole> for PoleJson {
+    fn from(pole: &ChildPole) -> Self {
+        match pole {
+            ChildPole::N => PoleJson::N,
+            ChildPole::S => PoleJson::S,
+            ChildPole::E => PoleJson::E,
+            ChildPole::W => PoleJson::W,
+        }
+    }
+}
+impl From<PoleJson> for ParentPole {
+    fn from(pole: PoleJson) -> Self {
+        match pole {
+            PoleJson::N => ParentPole::N,
+            PoleJson::S => ParentPole::S,
+            PoleJson::E => ParentPole::E,
+            PoleJson::W => ParentPole::W,
+        }
+    }
+}
+impl From<PoleJson> for ChildPole {
+    fn from(pole: PoleJson) -> Self {
+        match pole {
+            PoleJson::N => ChildPole::N,
+            PoleJson::S => ChildPole::S,
+            PoleJson::E => ChildPole::E,
+            PoleJson::W => ChildPole::W,
+        }
+    }
+}
+impl From<&Type> for TypeJson {
+    fn from(r#type: &Type) -> Self {
+        match r#type {
+            Type::Direct => TypeJson::Direct,
+            Type::Loopback => TypeJson::Loopback,
+        }
+    }
+}
+impl From<TypeJson> for Type {
+    fn from(r#type: TypeJson) -> Self {
+        match r#type {
+            TypeJson::Direct => Type::Direct,
+            TypeJson::Loopback => Type::Loopback,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::blueprint_graph::tests::{direct, node, relationship};
+    use doscenario_models::{blueprint::BlueprintModel, tag::TagModel};
+    use tonic::Code;
+
+    fn blueprint() -> BlueprintModel {
+        BlueprintModel {
+            id: 1,
+            project_id: 1,
+            created_by_id: None,
+            last_editor_id: None,
+            created_date: PrimitiveDateTime::MIN,
+            last_editing: PrimitiveDateTime::MIN,
+            uid: "uid".to_string(),
+            color: Some("#ff0000".to_string()),
+            title: "Story".to_string(),
+        }
+    }
+
+    fn to_json(graph: &BlueprintGraph) -> BlueprintJson {
+        let blueprint = blueprint();
+        BlueprintJson::from_export(&BlueprintExport {
+            blueprint: &blueprint,
+            graph,
+            tags: HashMap::new(),
+        })
+    }
+
+    fn rejected(graph: BlueprintGraph) -> Code {
+        to_json(&graph).validate().unwrap_err().code()
+    }
+
+    #[test]
+    fn exported_blueprints_are_imported_back() {
+        let mut start = node(1, true);
+        start.content = Some("Once upon a time".to_string());
+        start.summary = Some("Start".to_string());
+        start.color = Some("#00ff00".to_string());
+        let mut end = node(3, false);
+        end.x = 40;
+        end.y = -20;
+        let graph = BlueprintGraph::new(
+            vec![end, node(2, false), start],
+            vec![
+                direct(2, 3),
+                direct(1, 2),
+                relationship(3, 1, ParentPole::E, ChildPole::W, Type::Loopback),
+            ],
+        );
+        let blueprint = blueprint();
+        let tag = TagModel {
+            id: 1,
+            title: "act 1".to_string(),
+            primary: 1,
+            color: None,
+            project_id: Some(1),
+            created_by_id: None,
+        };
+        let json = BlueprintJson::from_export(&BlueprintExport {
+            blueprint: &blueprint,
+            graph: &graph,
+            tags: HashMap::from([(2, vec![&tag])]),
+        });
+        let parsed = BlueprintJson::parse(&serde_json::to_string_pretty(&json).unwrap()).unwrap();
+        assert_eq!(parsed, json);
+        assert_eq!(parsed.nodes[1].tags[0].title, "act 1");
+
+        let imported = parsed.to_graph();
+        for node in graph.nodes.iter() {
+            let copy = imported.node(node.id).unwrap();
+            assert_eq!(
+                (
+                    copy.is_root,
+                    &copy.content,
+                    &copy.summary,
+                    copy.x,
+                    copy.y,
+                    &copy.color
+                ),
+                (
+                    node.is_root,
+                    &node.content,
+                    &node.summary,
+                    node.x,
+                    node.y,
+                    &node.color
+                )
+            );
+        }
+        let mut relationships: Vec<_> = imported
+            .relationships
+            .iter()
+            .map(|rel| RelationshipModel {
+                blueprint_id: Some(1),
+                ..rel.clone()
+            })
+            .collect();
+        relationships.sort_by_key(|rel| rel.id);
+        let mut expected = graph.relationships.clone();
+        expected.sort_by_key(|rel| rel.id);
+        assert_eq!(relationships, expected);
+    }
+
+    #[test]
+    fn invalid_graphs_are_rejected() {
+        let nodes = || vec![node(1, true), node(2, false), node(3, false)];
+        let cycle = BlueprintGraph::new(nodes(), vec![direct(1, 2), direct(2, 3), direct(3, 2)]);
+        assert_eq!(rejected(cycle), Code::InvalidArgument);
+        let to_root = BlueprintGraph::new(nodes(), vec![direct(1, 2), direct(2, 1)]);
+        assert_eq!(rejected(to_root), Code::InvalidArgument);
+        let outside = BlueprintGraph::new(nodes(), vec![direct(1, 4)]);
+        assert_eq!(rejected(outside), Code::InvalidArgument);
+        let no_root = BlueprintGraph::new(vec![node(1, false)], vec![]);
+        assert_eq!(rejected(no_root), Code::InvalidArgument);
+        let two_roots = BlueprintGraph::new(vec![node(1, true), node(2, true)], vec![]);
+        assert_eq!(rejected(two_roots), Code::InvalidArgument);
+        let duplicate = BlueprintGraph::new(vec![node(1, true), node(1, false)], vec![]);
+        assert_eq!(rejected(duplicate), Code::InvalidArgument);
+
+        let loopback = relationship(3, 1, ParentPole::S, ChildPole::N, Type::Loopback);
+        let valid = BlueprintGraph::new(nodes(), vec![direct(1, 2), direct(2, 3), loopback]);
+        let mut json = to_json(&valid);
+        assert!(json.validate().is_ok());
+        json.version = FORMAT_VERSION + 1;
+        let content = serde_json::to_string(&json).unwrap();
+        assert_eq!(
+            BlueprintJson::parse(&content).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+}
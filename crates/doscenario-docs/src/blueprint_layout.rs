@@ -0,0 +1,395 @@
+//! Automatic placement of the nodes of a blueprint.
+//! Nodes are assigned a layer and a slot in this layer, following direct relationships from the root.
+//! Layers are then laid out in the direction given by the poles of the relationships.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use doscenario_models::sea_orm_active_enums::{ChildPole, ParentPole, Type};
+
+use crate::blueprint_graph::BlueprintGraph;
+
+/// Distance between two nodes of the same layer
+const NODE_SPACING: f64 = 300.0;
+/// Distance between two layers
+const LAYER_SPACING: f64 = 200.0;
+/// Number of barycenter sweeps used to reduce edge crossings
+const ORDERING_SWEEPS: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    /// Sugiyama-style layers ranked by the longest path from the root
+    Layered,
+    /// Tidy tree of the first parent of each node, parents are centered above their children
+    Tree,
+}
+
+/// Compute the position of every node of a blueprint
+/// The root node keeps its current position
+pub fn layout(graph: &BlueprintGraph, algorithm: Algorithm) -> HashMap<i32, (i32, i32)> {
+    let slots = match algorithm {
+        Algorithm::Layered => layered(graph),
+        Algorithm::Tree => tree(graph),
+    };
+    place(graph, slots)
+}
+
+/// Ids of the nodes, the root first, then the other nodes without direct parents, then by id
+fn sorted_ids(graph: &BlueprintGraph, parents: &HashMap<i32, Vec<i32>>) -> Vec<i32> {
+    let root = graph.root().ok().map(|r| r.id);
+    let mut ids: Vec<i32> = graph.nodes.iter().map(|n| n.id).collect();
+    ids.sort_by_key(|id| (Some(*id) != root, parents.contains_key(id), *id));
+    ids
+}
+
+/// Direct parents of every node, ignoring relationships to nodes outside the blueprint
+fn direct_parents(graph: &BlueprintGraph) -> HashMap<i32, Vec<i32>> {
+    let mut parents: HashMap<i32, Vec<i32>> = HashMap::new();
+    for node in graph.nodes.iter() {
+        for child in graph.direct_children(node.id) {
+            if graph.node(*child).is_some() {
+                parents.entry(*child).or_default().push(node.id);
+            }
+        }
+    }
+    parents
+}
+
+/// Assign each node a layer and a slot with the layered algorithm
+fn layered(graph: &BlueprintGraph) -> HashMap<i32, (usize, f64)> {
+    let parents = direct_parents(graph);
+    let ids = sorted_ids(graph, &parents);
+
+    // Rank the nodes reachable from the root with their longest path from it,
+    // then the other nodes with their longest path from a source of their own
+    let reachable: HashSet<i32> = match graph.root() {
+        Ok(root) => graph
+            .descendants(root.id)
+            .into_iter()
+            .chain([root.id])
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    let mut rank: HashMap<i32, usize> = HashMap::new();
+    let mut order = Vec::with_capacity(ids.len());
+    let (from_root, others): (Vec<i32>, Vec<i32>) =
+        ids.iter().partition(|id| reachable.contains(id));
+    rank_longest_paths(graph, &from_root, &parents, &mut rank, &mut order);
+    rank_longest_paths(graph, &others, &parents, &mut rank, &mut order);
+    // Nodes left on a direct cycle are put after every other layer
+    let last = rank.values().max().map_or(0, |r| r + 1);
+    let ordered: HashSet<i32> = order.iter().copied().collect();
+    for id in ids.iter() {
+        if !ordered.contains(id) {
+            rank.insert(*id, last);
+            order.push(*id);
+        }
+    }
+
+    let layer_count = rank.values().max().map_or(0, |r| r + 1);
+    let mut layers: Vec<Vec<i32>> = vec![Vec::new(); layer_count];
+    for id in order {
+        layers[rank[&id]].push(id);
+    }
+
+    // Reduce crossings by ordering each layer with the mean position of its neighbours
+    let children: HashMap<i32, Vec<i32>> = ids
+        .iter()
+        .map(|id| (*id, graph.direct_children(*id).to_vec()))
+        .collect();
+    for _ in 0..ORDERING_SWEEPS {
+        for i in 1..layers.len() {
+            let (above, below) = layers.split_at_mut(i);
+            order_layer(&mut below[0], above, &parents);
+        }
+        for i in (0..layers.len().saturating_sub(1)).rev() {
+            let (above, below) = layers.split_at_mut(i + 1);
+            order_layer(&mut above[i], below, &children);
+        }
+    }
+
+    let mut slots = HashMap::new();
+    for (layer, ids) in layers.iter().enumerate() {
+        let center = (ids.len() as f64 - 1.0) / 2.0;
+        for (index, id) in ids.iter().enumerate() {
+            slots.insert(*id, (layer, index as f64 - center));
+        }
+    }
+    slots
+}
+
+/// Rank a group of nodes with their longest path from the sources of the group, in topological order
+/// Relationships from nodes outside the group are ignored, nodes on a direct cycle are left unranked
+fn rank_longest_paths(
+    graph: &BlueprintGraph,
+    ids: &[i32],
+    parents: &HashMap<i32, Vec<i32>>,
+    rank: &mut HashMap<i32, usize>,
+    order: &mut Vec<i32>,
+) {
+    let group: HashSet<i32> = ids.iter().copied().collect();
+    let mut indegree: HashMap<i32, usize> = ids
+        .iter()
+        .map(|id| {
+            let degree = parents
+                .get(id)
+                .into_iter()
+                .flatten()
+                .filter(|p| group.contains(p))
+                .count();
+            (*id, degree)
+        })
+        .collect();
+    let mut queue: VecDeque<i32> = ids.iter().copied().filter(|id| indegree[id] == 0).collect();
+    while let Some(id) = queue.pop_front() {
+        let node_rank = *rank.entry(id).or_insert(0);
+        order.push(id);
+        for child in graph.direct_children(id) {
+            let Some(degree) = indegree.get_mut(child) else {
+                continue;
+            };
+            let child_rank = rank.entry(*child).or_insert(0);
+            *child_rank = (*child_rank).max(node_rank + 1);
+            *degree -= 1;
+            if *degree == 0 {
+                queue.push_back(*child);
+            }
+        }
+    }
+}
+
+/// Sort a layer by the barycenter of the neighbours of its nodes in the `fixed` layers
+/// Nodes without neighbours keep their index
+fn order_layer(layer: &mut [i32], fixed: &[Vec<i32>], neighbours: &HashMap<i32, Vec<i32>>) {
+    let index: HashMap<i32, f64> = fixed
+        .iter()
+        .flat_map(|ids| {
+            let center = (ids.len() as f64 - 1.0) / 2.0;
+            ids.iter()
+                .enumerate()
+                .map(move |(i, id)| (*id, i as f64 - center))
+        })
+        .collect();
+    let center = (layer.len() as f64 - 1.0) / 2.0;
+    let mut keyed: Vec<(f64, i32)> = layer
+        .iter()
+        .enumerate()
+        .map(|(i, id)| {
+            let positions: Vec<f64> = neighbours
+                .get(id)
+                .into_iter()
+                .flatten()
+                .filter_map(|n| index.get(n))
+                .copied()
+                .collect();
+            let key = if positions.is_empty() {
+                i as f64 - center
+            } else {
+                positions.iter().sum::<f64>() / positions.len() as f64
+            };
+            (key, *id)
+        })
+        .collect();
+    keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+    for (slot, (_, id)) in layer.iter_mut().zip(keyed) {
+        *slot = id;
+    }
+}
+
+/// Assign each node a layer and a slot with the tree algorithm
+/// Nodes not reachable from the root start their own trees placed after it
+fn tree(graph: &BlueprintGraph) -> HashMap<i32, (usize, f64)> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut roots = Vec::new();
+    for id in sorted_ids(graph, &direct_parents(graph)) {
+        if !visited.insert(id) {
+            continue;
+        }
+        roots.push(id);
+        let mut queue = VecDeque::from([id]);
+        while let Some(parent) = queue.pop_front() {
+            for child in graph.direct_children(parent) {
+                if graph.node(*child).is_some() && visited.insert(*child) {
+                    children.entry(parent).or_default().push(*child);
+                    queue.push_back(*child);
+                }
+            }
+        }
+    }
+
+    // Leaves take consecutive slots and parents are centered above their children
+    let mut slots: HashMap<i32, (usize, f64)> = HashMap::new();
+    let mut next_leaf = 0.0;
+    for root in roots {
+        let mut stack = vec![(root, 0, false)];
+        while let Some((id, depth, expanded)) = stack.pop() {
+            let node_children = children.get(&id).map(|c| c.as_slice()).unwrap_or_default();
+            if node_children.is_empty() {
+                slots.insert(id, (depth, next_leaf));
+                next_leaf += 1.0;
+            } else if expanded {
+                let first = slots[&node_children[0]].1;
+                let last = slots[&node_children[node_children.len() - 1]].1;
+                slots.insert(id, (depth, (first + last) / 2.0));
+            } else {
+                stack.push((id, depth, true));
+                stack.extend(node_children.iter().rev().map(|c| (*c, depth + 1, false)));
+            }
+        }
+    }
+    slots
+}
+
+/// Get the direction of the layers from the poles of direct relationships
+/// Each relationship points away from its parent pole and into its child pole,
+/// a child entered from the north is below its parent. Layers go from top to bottom by default
+fn flow_direction(graph: &BlueprintGraph) -> ParentPole {
+    let mut counts = [0; 4];
+    for rel in graph
+        .relationships
+        .iter()
+        .filter(|r| r.r#type == Type::Direct)
+    {
+        let from_parent = match rel.parent_pole {
+            ParentPole::S => 0,
+            ParentPole::E => 1,
+            ParentPole::N => 2,
+            ParentPole::W => 3,
+        };
+        let into_child = match rel.child_pole {
+            ChildPole::N => 0,
+            ChildPole::W => 1,
+            ChildPole::S => 2,
+            ChildPole::E => 3,
+        };
+        counts[from_parent] += 1;
+        counts[into_child] += 1;
+    }
+    let max = counts.iter().copied().max().unwrap_or(0);
+    match counts.iter().position(|c| *c == max) {
+        Some(1) if max > 0 => ParentPole::E,
+        Some(2) if max > 0 => ParentPole::N,
+        Some(3) if max > 0 => ParentPole::W,
+        _ => ParentPole::S,
+    }
+}
+
+/// Convert layers and slots to coordinates, anchored on the current position of the root
+fn place(graph: &BlueprintGraph, slots: HashMap<i32, (usize, f64)>) -> HashMap<i32, (i32, i32)> {
+    let direction = flow_direction(graph);
+    let to_point = |(layer, slot): (usize, f64)| {
+        let depth = layer as f64 * LAYER_SPACING;
+        let across = slot * NODE_SPACING;
+        match direction {
+            ParentPole::S => (across, depth),
+            ParentPole::N => (across, -depth),
+            ParentPole::E => (depth, across),
+            ParentPole::W => (-depth, across),
+        }
+    };
+    let (origin_x, origin_y) = match graph
+        .root()
+        .ok()
+        .and_then(|r| slots.get(&r.id).map(|s| (r, s)))
+    {
+        Some((root, slot)) => {
+            let (x, y) = to_point(*slot);
+            (root.x as f64 - x, root.y as f64 - y)
+        }
+        None => (0.0, 0.0),
+    };
+    slots
+        .into_iter()
+        .map(|(id, slot)| {
+            let (x, y) = to_point(slot);
+            (
+                id,
+                ((origin_x + x).round() as i32, (origin_y + y).round() as i32),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blueprint_graph::tests::{direct, node, relationship};
+
+    #[test]
+    fn direction_follows_parent_and_child_poles() {
+        let nodes = || vec![node(1, true), node(2, false), node(3, false)];
+        let down = BlueprintGraph::new(nodes(), vec![direct(1, 2), direct(2, 3)]);
+        assert_eq!(flow_direction(&down), ParentPole::S);
+        // Parent poles are tied, the children are entered from the west
+        let right = BlueprintGraph::new(
+            nodes(),
+            vec![
+                relationship(1, 2, ParentPole::S, ChildPole::W, Type::Direct),
+                relationship(2, 3, ParentPole::E, ChildPole::W, Type::Direct),
+            ],
+        );
+        assert_eq!(flow_direction(&right), ParentPole::E);
+        let up = BlueprintGraph::new(
+            nodes(),
+            vec![
+                relationship(1, 2, ParentPole::W, ChildPole::S, Type::Direct),
+                relationship(2, 3, ParentPole::N, ChildPole::S, Type::Direct),
+                relationship(3, 1, ParentPole::E, ChildPole::W, Type::Loopback),
+            ],
+        );
+        assert_eq!(flow_direction(&up), ParentPole::N);
+    }
+
+    #[test]
+    fn layers_are_ranked_from_the_root() {
+        let mut root = node(1, true);
+        root.x = 100;
+        root.y = 50;
+        // The orphan chain 4 -> 5 -> 6 leads to 2, which stays right below the root
+        let graph = BlueprintGraph::new(
+            vec![
+                root,
+                node(2, false),
+                node(3, false),
+                node(4, false),
+                node(5, false),
+                node(6, false),
+            ],
+            vec![
+                direct(1, 2),
+                direct(2, 3),
+                direct(4, 5),
+                direct(5, 6),
+                direct(6, 2),
+            ],
+        );
+        let positions = layout(&graph, Algorithm::Layered);
+        assert_eq!(positions[&1], (100, 50));
+        assert_eq!(positions[&2].1, 250);
+        assert_eq!(positions[&3].1, 450);
+        assert_eq!(positions[&4].1, 50);
+        assert_eq!(positions[&5].1, 250);
+        assert_eq!(positions[&6].1, 450);
+    }
+
+    #[test]
+    fn tree_parents_are_centered_above_their_children() {
+        let graph = BlueprintGraph::new(
+            vec![
+                node(1, true),
+                node(2, false),
+                node(3, false),
+                node(4, false),
+            ],
+            vec![
+                relationship(1, 2, ParentPole::E, ChildPole::W, Type::Direct),
+                relationship(1, 3, ParentPole::E, ChildPole::W, Type::Direct),
+                relationship(3, 4, ParentPole::E, ChildPole::W, Type::Direct),
+            ],
+        );
+        let positions = layout(&graph, Algorithm::Tree);
+        assert_eq!(positions[&1], (0, 0));
+        assert_eq!(positions[&2], (200, -150));
+        assert_eq!(positions[&3], (200, 150));
+        assert_eq!(positions[&4], (400, 150));
+    }
+}
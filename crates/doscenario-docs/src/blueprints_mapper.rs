@@ -0,0 +1,121 @@
+use doscenario_models::{
+    blueprint, node, relationship,
+    sea_orm_active_enums::{ChildPole, ParentPole, Type},
+};
+
+use crate::blueprints::{
+    NodeEntity, OpenBlueprintResponse, Pole, RelationshipEntity, RelationshipType,
+};
+
+impl From<blueprint::BlueprintModel> for OpenBlueprintResponse {
+    fn from(blueprint: blueprint::BlueprintModel) -> Self {
+        OpenBlueprintResponse {
+            id: blueprint.id,
+            uid: blueprint.uid,
+            project_id: blueprint.project_id,
+            title: blueprint.title,
+            color: blueprint.color.unwrap_or_default(),
+            created_by_id: blueprint.created_by_id.unwrap_or_default(),
+            last_editor_id: blueprint.last_editor_id.unwrap_or_default(),
+            created_date: blueprint.created_date.to_string(),
+            last_editing: blueprint.last_editing.to_string(),
+            nodes: vec![],
+            relationships: vec![],
+        }
+    }
+}
+
+impl From<node::NodeModel> for NodeEntity {
+    fn from(node: node::NodeModel) -> Self {
+        NodeEntity {
+            id: node.id,
+            blueprint_id: node.blueprint_id.unwrap_or_default(),
+            is_root: node.is_root != 0,
+            content: node.content.unwrap_or_default(),
+            summary: node.summary.unwrap_or_default(),
+            x: node.x,
+            y: node.y,
+            // Locks are leases kept in memory, they are set when the node is sent
+            locked: false,
+            color: node.color.unwrap_or_default(),
+            created_by_id: node.created_by_id.unwrap_or_default(),
+            last_editor_id: node.last_editor_id.unwrap_or_default(),
+            created_date: node.created_date.to_string(),
+            last_editing: node.last_editing.to_string(),
+            lock_user_id: String::new(),
+            lock_session_id: 0,
+        }
+    }
+}
+
+impl From<relationship::RelationshipModel> for RelationshipEntity {
+    fn from(relationship: relationship::RelationshipModel) -> Self {
+        RelationshipEntity {
+            id: relationship.id,
+            blueprint_id: relationship.blueprint_id.unwrap_or_default(),
+            parent_id: relationship.parent_id,
+            child_id: relationship.child_id,
+            parent_pole: Pole::from(relationship.parent_pole) as i32,
+            child_pole: Pole::from(relationship.child_pole) as i32,
+            r#type: RelationshipType::from(relationship.r#type) as i32,
+        }
+    }
+}
+
+impl From<ParentPole> for Pole {
+    fn from(pole: ParentPole) -> Self {
+        match pole {
+            ParentPole::N => Pole::N,
+            ParentPole::S => Pole::S,
+            ParentPole::E => Pole::E,
+            ParentPole::W => Pole::W,
+        }
+    }
+}
+impl From<ChildPole> for Pole {
+    fn from(pole: ChildPole) -> Self {
+        match pole {
+            ChildPole::N => Pole::N,
+            ChildPole::S => Pole::S,
+            ChildPole::E => Pole::E,
+            ChildPole::W => Pole::W,
+        }
+    }
+}
+impl From<Pole> for ParentPole {
+    fn from(pole: Pole) -> Self {
+        match pole {
+            Pole::N => ParentPole::N,
+            Pole::S => ParentPole::S,
+            Pole::E => ParentPole::E,
+            Pole::W => ParentPole::W,
+        }
+    }
+}
+impl From<Pole> for ChildPole {
+    fn from(pole: Pole) -> Self {
+        match pole {
+            Pole::N => ChildPole::N,
+            Pole::S => ChildPole::S,
+            Pole::E => ChildPole::E,
+            Pole::W => ChildPole::W,
+        }
+    }
+}
+
+impl From<Type> for RelationshipType {
+    fn from(r#type: Type) -> Self {
+        match r#type {
+            Type::Direct => RelationshipType::Direct,
+            Type::Loopback => RelationshipType::Loopback,
+        }
+    }
+}
+impl From<RelationshipType> for Type {
+    fn from(r#type: RelationshipType) -> Self {
+        match r#type {
+            RelationshipType::Direct => Type::Direct,
+            RelationshipType::Loopback => Type::Loopback,
+        }
+    }
+}
@@ -0,0 +1,952 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    blueprint_export::BlueprintExport,
+    blueprint_graph::BlueprintGraph,
+    blueprint_json::BlueprintJson,
+    blueprint_layout::{self, Algorithm},
+    blueprints::{blueprint_event::Event, *},
+    docs,
+    docs_cache::{DocKey, DocsCache, SERVER_SESSION},
+    node_locks::{NodeLock, NodeLocks},
+    project_access::ProjectAccess,
+    queries, summarizer,
+    utils::{get_snowflake, unpack_req},
+};
+use dashmap::DashMap;
+use doscenario_models::{
+    blueprint::BlueprintModel, node_tag::NodeTagModel, sea_orm_active_enums::ProjectRole,
+    tag::TagModel,
+};
+use tokio::sync::{
+    mpsc::{self, Sender},
+    Mutex,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+pub type SenderChan = Sender<Result<BlueprintEvent, Status>>;
+
+#[derive(Debug)]
+pub struct BlueprintSession {
+    user_id: String,
+    tx: Arc<SenderChan>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlueprintsService {
+    // Map a blueprint id to a map of session id with the session user and sender channel
+    blueprint_streams: Arc<DashMap<i32, HashMap<i64, BlueprintSession>>>,
+    node_locks: Arc<NodeLocks>,
+    // Node contents and summaries are edited through the docs cache
+    doc_cache: Arc<DocsCache>,
+    // Map a node text to the sessions editing it
+    node_text_sessions: Arc<DashMap<DocKey, HashSet<i64>>>,
+    // Held while a relationship is validated and created so that concurrent ones can't form a cycle
+    graph_lock: Arc<Mutex<()>>,
+    // Every call is checked against the members of the project of its blueprint
+    project_access: Arc<ProjectAccess>,
+}
+impl BlueprintsService {
+    pub fn new(doc_cache: Arc<DocsCache>, project_access: Arc<ProjectAccess>) -> Self {
+        let service = Self {
+            blueprint_streams: Arc::new(DashMap::new()),
+            node_locks: Arc::new(NodeLocks::from_env()),
+            doc_cache,
+            node_text_sessions: Arc::new(DashMap::new()),
+            graph_lock: Arc::new(Mutex::new(())),
+            project_access,
+        };
+        let expiry_service = service.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_secs(1);
+            loop {
+                tokio::time::sleep(delay).await;
+                let expired = expiry_service.node_locks.release_expired();
+                expiry_service.broadcast_unlocks(expired).await;
+            }
+        });
+        service
+    }
+}
+
+#[tonic::async_trait]
+impl blueprints_server::Blueprints for BlueprintsService {
+    type SubscribeBlueprintStream = ReceiverStream<Result<BlueprintEvent, Status>>;
+
+    /// Open a blueprint, return the blueprint info with all its nodes and relationships
+    async fn open_blueprint(
+        &self,
+        request: Request<BlueprintRequest>,
+    ) -> Result<Response<OpenBlueprintResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        log::info!("Open blueprint request: {:?}", data);
+        let res = self.open_response(data.id).await.map_err(|e| {
+            log::error!("Error opening blueprint: {:?}", e);
+            e
+        })?;
+        Ok(Response::new(res))
+    }
+
+    async fn export_blueprint(
+        &self,
+        request: Request<ExportBlueprintRequest>,
+    ) -> Result<Response<ExportBlueprintResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let format = ExportFormat::from_i32(data.format)
+            .ok_or(Status::invalid_argument("Invalid export format"))?;
+        let (blueprint, graph, node_tags, tags) = get_export_data(data.id).await?;
+        let export = BlueprintExport {
+            blueprint: &blueprint,
+            graph: &graph,
+            tags: tags_by_node(&node_tags, &tags),
+        };
+        let (content, mime_type) = match format {
+            ExportFormat::Dot => (export.to_dot(), "text/vnd.graphviz"),
+            ExportFormat::Svg => (export.to_svg(), "image/svg+xml"),
+            ExportFormat::Json => (export.to_json(), "application/json"),
+        };
+        Ok(Response::new(ExportBlueprintResponse {
+            content,
+            mime_type: mime_type.to_string(),
+        }))
+    }
+
+    async fn import_blueprint(
+        &self,
+        request: Request<ImportBlueprintRequest>,
+    ) -> Result<Response<OpenBlueprintResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_project(&user_id.0, data.project_id, ProjectRole::Editor)
+            .await?;
+        let blueprint = BlueprintJson::parse(&data.content)?;
+        let id = queries::import_blueprint(&data.project_id, &user_id.0, &blueprint).await?;
+        log::info!(
+            "Imported blueprint {} with {} nodes in project {}",
+            id,
+            blueprint.nodes.len(),
+            data.project_id
+        );
+        Ok(Response::new(self.open_response(id).await?))
+    }
+
+    /// Copy a blueprint with the texts of its nodes as they are currently edited
+    async fn duplicate_blueprint(
+        &self,
+        request: Request<DuplicateBlueprintRequest>,
+    ) -> Result<Response<OpenBlueprintResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let (blueprint, graph, node_tags, tags) = get_export_data(data.id).await?;
+        let export = BlueprintExport {
+            blueprint: &blueprint,
+            graph: &graph,
+            tags: tags_by_node(&node_tags, &tags),
+        };
+        let mut copy = BlueprintJson::from_export(&export);
+        for node in copy.nodes.iter_mut() {
+            if let Some(content) = self
+                .doc_cache
+                .get_cached_content(DocKey::NodeContent(node.id))
+            {
+                node.content = Some(content);
+            }
+            if let Some(summary) = self
+                .doc_cache
+                .get_cached_content(DocKey::NodeSummary(node.id))
+            {
+                node.summary = Some(summary);
+            }
+        }
+        if !data.title.trim().is_empty() {
+            copy.title = data.title.trim().to_string();
+        }
+        copy.validate()?;
+        let project_id = match data.project_id {
+            0 => blueprint.project_id,
+            id => id,
+        };
+        self.project_access
+            .check_project(&user_id.0, project_id, ProjectRole::Editor)
+            .await?;
+        let id = queries::import_blueprint(&project_id, &user_id.0, &copy).await?;
+        log::info!("Duplicated blueprint {} to blueprint {}", data.id, id);
+        Ok(Response::new(self.open_response(id).await?))
+    }
+
+    async fn close_blueprint(
+        &self,
+        request: Request<BlueprintIdentityRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        self.close_session_texts(data.session_id).await;
+        let locks = self.node_locks.release_session(data.session_id);
+        self.broadcast_unlocks(locks).await;
+        self.broadcast(
+            data.id,
+            Event::Close(BlueprintEventClose {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    async fn subscribe_blueprint(
+        &self,
+        request: Request<BlueprintIdentityRequest>,
+    ) -> Result<Response<Self::SubscribeBlueprintStream>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let (tx, rx) = mpsc::channel(64);
+        let user = queries::get_user(&user_id.0).await?;
+
+        let session_id = get_snowflake().await;
+        self.broadcast(
+            data.id,
+            Event::Open(BlueprintEventOpen {
+                id: data.id,
+                user_id: user_id.0.clone(),
+                user_name: user.name,
+                session_id,
+            }),
+        )
+        .await;
+        let tx = Arc::new(tx);
+        self.blueprint_streams.entry(data.id).or_default().insert(
+            session_id,
+            BlueprintSession {
+                user_id: user_id.0.clone(),
+                tx: tx.clone(),
+            },
+        );
+        log::info!(
+            "Stream created session_id: {session_id}, blueprint {}",
+            data.id
+        );
+
+        tx.send(Ok(BlueprintEvent {
+            event: Some(Event::Subscribed(BlueprintEventSubscribed {
+                id: data.id,
+                session_id,
+            })),
+        }))
+        .await
+        .map_err(|_| Status::internal("Cannot send message"))?;
+
+        self.attach_unsubscribe(tx, session_id, data.id, user_id.0);
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn create_node(
+        &self,
+        request: Request<CreateNodeRequest>,
+    ) -> Result<Response<NodeEntity>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let color = Some(data.color).filter(|c| !c.is_empty());
+        let id = queries::create_node(&data.id, data.x, data.y, &color, &user_id.0).await?;
+        let node: NodeEntity = queries::get_node(&id).await?.into();
+        self.broadcast(
+            data.id,
+            Event::CreateNode(BlueprintEventCreateNode {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                node: Some(node.clone()),
+            }),
+        )
+        .await;
+        Ok(Response::new(node))
+    }
+
+    async fn move_node(&self, request: Request<MoveNodeRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        self.node_locks.check(data.node_id, data.session_id)?;
+        if !queries::move_node(&data.id, &data.node_id, data.x, data.y, &user_id.0).await? {
+            return Err(Status::not_found("Node not found"));
+        }
+        self.broadcast(
+            data.id,
+            Event::MoveNode(BlueprintEventMoveNode {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                node_id: data.node_id,
+                x: data.x,
+                y: data.y,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    async fn auto_layout(
+        &self,
+        request: Request<AutoLayoutRequest>,
+    ) -> Result<Response<AutoLayoutResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let algorithm = match LayoutAlgorithm::from_i32(data.algorithm) {
+            Some(LayoutAlgorithm::Layered) => Algorithm::Layered,
+            Some(LayoutAlgorithm::Tree) => Algorithm::Tree,
+            None => return Err(Status::invalid_argument("Invalid layout algorithm")),
+        };
+        let graph = get_graph(data.id).await?;
+        graph.root()?;
+        let layout = blueprint_layout::layout(&graph, algorithm);
+        let mut positions: Vec<NodePosition> = graph
+            .nodes
+            .iter()
+            .filter_map(|node| {
+                let (x, y) = layout.get(&node.id).copied()?;
+                (node.x != x || node.y != y).then_some(NodePosition {
+                    node_id: node.id,
+                    x,
+                    y,
+                })
+            })
+            .collect();
+        positions.sort_by_key(|p| p.node_id);
+        for position in positions.iter() {
+            self.node_locks.check(position.node_id, data.session_id)?;
+        }
+        let moves: Vec<(i32, i32, i32)> = positions.iter().map(|p| (p.node_id, p.x, p.y)).collect();
+        queries::move_nodes(&data.id, &moves, &user_id.0).await?;
+        for position in positions.iter() {
+            self.broadcast(
+                data.id,
+                Event::MoveNode(BlueprintEventMoveNode {
+                    id: data.id,
+                    user_id: user_id.0.clone(),
+                    session_id: data.session_id,
+                    node_id: position.node_id,
+                    x: position.x,
+                    y: position.y,
+                }),
+            )
+            .await;
+        }
+        Ok(Response::new(AutoLayoutResponse { positions }))
+    }
+
+    async fn remove_node(&self, request: Request<NodeRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let node = queries::get_node(&data.node_id).await?;
+        if node.blueprint_id != Some(data.id) {
+            return Err(Status::not_found("Node not found"));
+        }
+        if node.is_root != 0 {
+            return Err(Status::failed_precondition(
+                "The root node cannot be removed",
+            ));
+        }
+        self.node_locks.check(data.node_id, data.session_id)?;
+        let relationship_ids = queries::delete_node(&data.id, &data.node_id).await?;
+        self.node_locks.release(data.node_id, data.session_id);
+        for key in [
+            DocKey::NodeContent(data.node_id),
+            DocKey::NodeSummary(data.node_id),
+        ] {
+            self.node_text_sessions.remove(&key);
+            self.doc_cache.clear_doc_cache(key).await;
+            self.project_access.forget(key);
+        }
+        self.broadcast(
+            data.id,
+            Event::RemoveNode(BlueprintEventRemoveNode {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                node_id: data.node_id,
+                relationship_ids,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    async fn lock_node(
+        &self,
+        request: Request<NodeRequest>,
+    ) -> Result<Response<LockNodeResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let node = queries::get_node(&data.node_id).await?;
+        if node.blueprint_id != Some(data.id) {
+            return Err(Status::not_found("Node not found"));
+        }
+        let (acquired, expired) =
+            self.node_locks
+                .acquire(data.id, data.node_id, data.session_id, &user_id.0)?;
+        if let Some(expired) = expired {
+            self.broadcast_unlocks(vec![(data.node_id, expired)]).await;
+        }
+        if acquired {
+            self.broadcast(
+                data.id,
+                Event::LockNode(BlueprintEventLockNode {
+                    id: data.id,
+                    user_id: user_id.0,
+                    session_id: data.session_id,
+                    node_id: data.node_id,
+                }),
+            )
+            .await;
+        }
+        Ok(Response::new(LockNodeResponse {
+            lease_ms: self.node_locks.lease().as_millis() as u64,
+        }))
+    }
+
+    async fn unlock_node(&self, request: Request<NodeRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        if !self.node_locks.release(data.node_id, data.session_id) {
+            return Err(Status::failed_precondition(
+                "Node is not locked by this session",
+            ));
+        }
+        self.broadcast(
+            data.id,
+            Event::UnlockNode(BlueprintEventUnlockNode {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                node_id: data.node_id,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    /// Open the content or the summary of a node, return the text and its change id
+    async fn open_node_text(
+        &self,
+        request: Request<NodeTextRequest>,
+    ) -> Result<Response<OpenNodeTextResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let key = node_text_key(data.node_id, data.field)?;
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let node = queries::get_node(&data.node_id).await?;
+        if node.blueprint_id != Some(data.id) {
+            return Err(Status::not_found("Node not found"));
+        }
+        let (content, change_id) = self.doc_cache.register_doc(key).await?;
+        self.node_text_sessions
+            .entry(key)
+            .or_default()
+            .insert(data.session_id);
+        Ok(Response::new(OpenNodeTextResponse { content, change_id }))
+    }
+
+    /// Apply changes to the content or the summary of a node and broadcast the transformed changes
+    async fn write_node_text(
+        &self,
+        request: Request<NodeTextWriteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let key = node_text_key(data.node_id, data.field)?;
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Editor)
+            .await?;
+        match self.node_text_sessions.get(&key) {
+            Some(sessions) if sessions.contains(&data.session_id) => (),
+            _ => {
+                return Err(Status::failed_precondition(
+                    "Node text is not opened by this session",
+                ))
+            }
+        };
+        self.node_locks.check(data.node_id, data.session_id)?;
+        let changes = data.changes.into_iter().filter_map(|c| c.change).collect();
+        let (changes, change_id) = self
+            .doc_cache
+            .update_doc(data.session_id, &user_id.0, key, changes, data.change_id)
+            .await?;
+        self.broadcast(
+            data.id,
+            Event::WriteNodeText(BlueprintEventWriteNodeText {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                node_id: data.node_id,
+                field: data.field,
+                changes: changes
+                    .into_iter()
+                    .map(|c| docs::Change { change: Some(c) })
+                    .collect(),
+                change_id,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    async fn close_node_text(
+        &self,
+        request: Request<NodeTextRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let key = node_text_key(data.node_id, data.field)?;
+        self.release_node_text_session(key, data.session_id).await;
+        Ok(Response::new(()))
+    }
+
+    /// Fill the summary of a node from its content
+    /// If the summary is opened the replacement goes through the docs cache as a write of the server
+    async fn summarize_node(
+        &self,
+        request: Request<SummarizeNodeRequest>,
+    ) -> Result<Response<SummaryResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let node = queries::get_node(&data.node_id).await?;
+        if node.blueprint_id != Some(data.id) {
+            return Err(Status::not_found("Node not found"));
+        }
+        self.node_locks.check(data.node_id, data.session_id)?;
+        let content = self
+            .doc_cache
+            .get_content(DocKey::NodeContent(data.node_id))
+            .await?;
+        let summary =
+            summarizer::summarize(&content, summarizer::max_sentences(data.max_sentences));
+        let key = DocKey::NodeSummary(data.node_id);
+        let (changes, change_id) = match self
+            .doc_cache
+            .replace_doc(&user_id.0, key, summary.clone())
+            .await?
+        {
+            Some(write) => write,
+            None => {
+                queries::set_node_summary(&data.node_id, &summary).await?;
+                let replace = docs::change::Change::Replace(docs::Replace {
+                    content: summary.clone(),
+                });
+                (vec![replace], 0)
+            }
+        };
+        self.broadcast(
+            data.id,
+            Event::WriteNodeText(BlueprintEventWriteNodeText {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: SERVER_SESSION,
+                node_id: data.node_id,
+                field: NodeField::Summary as i32,
+                changes: changes
+                    .into_iter()
+                    .map(|c| docs::Change { change: Some(c) })
+                    .collect(),
+                change_id,
+            }),
+        )
+        .await;
+        Ok(Response::new(SummaryResponse { summary }))
+    }
+
+    async fn create_relationship(
+        &self,
+        request: Request<CreateRelationshipRequest>,
+    ) -> Result<Response<RelationshipEntity>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        let parent_pole = Pole::from_i32(data.parent_pole)
+            .ok_or(Status::invalid_argument("Invalid parent pole"))?;
+        let child_pole = Pole::from_i32(data.child_pole)
+            .ok_or(Status::invalid_argument("Invalid child pole"))?;
+        let r#type = RelationshipType::from_i32(data.r#type)
+            .ok_or(Status::invalid_argument("Invalid relationship type"))?;
+        let guard = self.graph_lock.lock().await;
+        let graph = get_graph(data.id).await?;
+        graph.validate_relationship(data.parent_id, data.child_id, &r#type.into())?;
+        let id = queries::create_relationship(
+            &data.id,
+            &data.parent_id,
+            &data.child_id,
+            parent_pole.into(),
+            child_pole.into(),
+            r#type.into(),
+        )
+        .await?;
+        drop(guard);
+        let relationship: RelationshipEntity = queries::get_relationship(&id).await?.into();
+        self.broadcast(
+            data.id,
+            Event::CreateRelationship(BlueprintEventCreateRelationship {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                relationship: Some(relationship.clone()),
+            }),
+        )
+        .await;
+        Ok(Response::new(relationship))
+    }
+
+    async fn remove_relationship(
+        &self,
+        request: Request<RelationshipRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Editor)
+            .await?;
+        self.check_session(data.id, data.session_id, &user_id.0)?;
+        if !queries::delete_relationship(&data.id, &data.relationship_id).await? {
+            return Err(Status::not_found("Relationship not found"));
+        }
+        self.broadcast(
+            data.id,
+            Event::RemoveRelationship(BlueprintEventRemoveRelationship {
+                id: data.id,
+                user_id: user_id.0,
+                session_id: data.session_id,
+                relationship_id: data.relationship_id,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    async fn get_paths_to_node(
+        &self,
+        request: Request<NodeQueryRequest>,
+    ) -> Result<Response<NodePathsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let graph = get_node_graph(data.id, data.node_id).await?;
+        let (paths, truncated) = graph.paths_to(data.node_id)?;
+        Ok(Response::new(NodePathsResponse {
+            paths: paths
+                .into_iter()
+                .map(|node_ids| NodePath { node_ids })
+                .collect(),
+            truncated,
+        }))
+    }
+
+    async fn get_descendants(
+        &self,
+        request: Request<NodeQueryRequest>,
+    ) -> Result<Response<NodeIdsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let graph = get_node_graph(data.id, data.node_id).await?;
+        Ok(Response::new(NodeIdsResponse {
+            node_ids: graph.descendants(data.node_id),
+        }))
+    }
+
+    async fn get_ancestors(
+        &self,
+        request: Request<NodeQueryRequest>,
+    ) -> Result<Response<NodeIdsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let graph = get_node_graph(data.id, data.node_id).await?;
+        Ok(Response::new(NodeIdsResponse {
+            node_ids: graph.ancestors(data.node_id),
+        }))
+    }
+
+    async fn get_orphans(
+        &self,
+        request: Request<BlueprintRequest>,
+    ) -> Result<Response<NodeIdsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let graph = get_graph(data.id).await?;
+        Ok(Response::new(NodeIdsResponse {
+            node_ids: graph.orphans()?,
+        }))
+    }
+
+    async fn get_dead_ends(
+        &self,
+        request: Request<BlueprintRequest>,
+    ) -> Result<Response<NodeIdsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_blueprint(&user_id.0, data.id, ProjectRole::Viewer)
+            .await?;
+        let graph = get_graph(data.id).await?;
+        Ok(Response::new(NodeIdsResponse {
+            node_ids: graph.dead_ends()?,
+        }))
+    }
+}
+
+/// Get a blueprint with its graph, its node tags and the tags they use
+async fn get_export_data(
+    blueprint_id: i32,
+) -> Result<
+    (
+        BlueprintModel,
+        BlueprintGraph,
+        Vec<NodeTagModel>,
+        Vec<TagModel>,
+    ),
+    Status,
+> {
+    tokio::try_join!(
+        queries::get_blueprint(&blueprint_id),
+        get_graph(blueprint_id),
+        queries::get_blueprint_node_tags(&blueprint_id),
+        queries::get_blueprint_tags(&blueprint_id)
+    )
+}
+
+/// Map a node id to its tags
+fn tags_by_node<'a>(
+    node_tags: &[NodeTagModel],
+    tags: &'a [TagModel],
+) -> HashMap<i32, Vec<&'a TagModel>> {
+    let mut by_node: HashMap<i32, Vec<&TagModel>> = HashMap::new();
+    for node_tag in node_tags.iter() {
+        if let Some(tag) = tags.iter().find(|t| t.id == node_tag.tag_id) {
+            by_node.entry(node_tag.node_id).or_default().push(tag);
+        }
+    }
+    by_node
+}
+
+/// Load the graph of a blueprint
+async fn get_graph(blueprint_id: i32) -> Result<BlueprintGraph, Status> {
+    let (nodes, relationships) = tokio::try_join!(
+        queries::get_blueprint_nodes(&blueprint_id),
+        queries::get_blueprint_relationships(&blueprint_id)
+    )?;
+    Ok(BlueprintGraph::new(nodes, relationships))
+}
+
+/// Load the graph of a blueprint and check that it contains a node
+async fn get_node_graph(blueprint_id: i32, node_id: i32) -> Result<BlueprintGraph, Status> {
+    let graph = get_graph(blueprint_id).await?;
+    if graph.node(node_id).is_none() {
+        return Err(Status::not_found("Node not found"));
+    }
+    Ok(graph)
+}
+
+/// Get the cache key of the content or the summary of a node
+fn node_text_key(node_id: i32, field: i32) -> Result<DocKey, Status> {
+    match NodeField::from_i32(field) {
+        Some(NodeField::Content) => Ok(DocKey::NodeContent(node_id)),
+        Some(NodeField::Summary) => Ok(DocKey::NodeSummary(node_id)),
+        None => Err(Status::invalid_argument("Invalid node field")),
+    }
+}
+
+impl BlueprintsService {
+    /// Remove a session from the editors of a node text
+    /// The text is saved and removed from the cache when its last editor leaves
+    async fn release_node_text_session(&self, key: DocKey, session_id: i64) {
+        self.doc_cache.remove_session(key, session_id);
+        let is_empty = match self.node_text_sessions.get_mut(&key) {
+            Some(mut sessions) => {
+                sessions.remove(&session_id);
+                sessions.is_empty()
+            }
+            None => return,
+        };
+        if is_empty
+            && self
+                .node_text_sessions
+                .remove_if(&key, |_, sessions| sessions.is_empty())
+                .is_some()
+        {
+            if let Err(e) = self.doc_cache.remove_doc(key).await {
+                log::error!("Error removing node text from cache: {:?}", e);
+            }
+        }
+    }
+
+    /// Close every node text opened by a session
+    async fn close_session_texts(&self, session_id: i64) {
+        let keys: Vec<DocKey> = self
+            .node_text_sessions
+            .iter()
+            .filter(|entry| entry.value().contains(&session_id))
+            .map(|entry| *entry.key())
+            .collect();
+        for key in keys {
+            self.release_node_text_session(key, session_id).await;
+        }
+    }
+
+    /// Get a blueprint with all its nodes, their locks and its relationships
+    async fn open_response(&self, id: i32) -> Result<OpenBlueprintResponse, Status> {
+        let (blueprint, nodes, relationships) = tokio::try_join!(
+            queries::get_blueprint(&id),
+            queries::get_blueprint_nodes(&id),
+            queries::get_blueprint_relationships(&id)
+        )?;
+        let mut res: OpenBlueprintResponse = blueprint.into();
+        res.nodes = nodes
+            .into_iter()
+            .map(|n| {
+                let mut node: NodeEntity = n.into();
+                let lock = self.node_locks.get(node.id);
+                node.locked = lock.is_some();
+                if let Some(lock) = lock {
+                    node.lock_user_id = lock.user_id;
+                    node.lock_session_id = lock.session_id;
+                }
+                node
+            })
+            .collect();
+        res.relationships = relationships.into_iter().map(|r| r.into()).collect();
+        Ok(res)
+    }
+
+    /// Check that a session of a user is subscribed to a blueprint
+    fn check_session(
+        &self,
+        blueprint_id: i32,
+        session_id: i64,
+        user_id: &str,
+    ) -> Result<(), Status> {
+        let subs = self.blueprint_streams.get(&blueprint_id);
+        match subs.as_ref().and_then(|subs| subs.get(&session_id)) {
+            Some(session) if session.user_id == user_id => Ok(()),
+            Some(_) => Err(Status::permission_denied("Session belongs to another user")),
+            None => Err(Status::failed_precondition(
+                "Session is not subscribed to this blueprint",
+            )),
+        }
+    }
+
+    /// Notify subscribers that nodes were unlocked
+    async fn broadcast_unlocks(&self, locks: Vec<(i32, NodeLock)>) {
+        for (node_id, lock) in locks {
+            self.broadcast(
+                lock.blueprint_id,
+                Event::UnlockNode(BlueprintEventUnlockNode {
+                    id: lock.blueprint_id,
+                    user_id: lock.user_id,
+                    session_id: lock.session_id,
+                    node_id,
+                }),
+            )
+            .await;
+        }
+    }
+
+    /// Send an event to every session subscribed to a blueprint
+    async fn broadcast(&self, blueprint_id: i32, event: Event) {
+        let subs: Vec<Arc<SenderChan>> = match self.blueprint_streams.get(&blueprint_id) {
+            Some(subs) => subs.values().map(|session| session.tx.clone()).collect(),
+            None => return,
+        };
+        let res = futures::future::join_all(subs.iter().map(|tx| {
+            tx.send(Ok(BlueprintEvent {
+                event: Some(event.clone()),
+            }))
+        }))
+        .await;
+        for r in res {
+            if let Err(e) = r {
+                log::error!("Error sending event to blueprint {}: {:?}", blueprint_id, e);
+            }
+        }
+    }
+
+    fn attach_unsubscribe(
+        &self,
+        tx: Arc<SenderChan>,
+        session_id: i64,
+        blueprint_id: i32,
+        user_id: String,
+    ) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            tx.closed().await;
+            log::info!("Stream closed session_id: {session_id}, blueprint {blueprint_id}");
+            match service.blueprint_streams.get_mut(&blueprint_id) {
+                Some(mut subs) => subs.remove(&session_id),
+                None => return,
+            };
+            service
+                .blueprint_streams
+                .remove_if(&blueprint_id, |_, subs| subs.is_empty());
+            service.close_session_texts(session_id).await;
+            let locks = service.node_locks.release_session(session_id);
+            service.broadcast_unlocks(locks).await;
+            service
+                .broadcast(
+                    blueprint_id,
+                    Event::Close(BlueprintEventClose {
+                        id: blueprint_id,
+                        user_id,
+                        session_id,
+                    }),
+                )
+                .await;
+        });
+    }
+}
@@ -1,38 +1,284 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::docs::change::Change;
-use crate::{queries, utils::RemoveRange};
+use crate::docs::{
+    self, change::Change, ChangesSinceResponse, DocEventCursor, DocEventWrite, Insert, Remove,
+    Replace,
+};
+use crate::{
+    docs_crdt::CrdtDoc,
+    ot, queries, summarizer, text,
+    utils::get_snowflake,
+    wal::{Wal, WalRecord},
+};
 use dashmap::DashMap;
 use futures::future::join_all;
-use log::warn;
-use logging_timer::time;
+use ropey::Rope;
 use tokio::time::{self};
 use tonic::Status;
 
+/// Number of applied writes kept per document to transform concurrent writes
+const HISTORY_SIZE: usize = 500;
+/// Session of the writes made by the server itself, they can't be undone
+pub const SERVER_SESSION: i64 = 0;
+
+/// A write applied to a document, identified by the change id it produced
 #[derive(Debug, Clone)]
 struct ChangeEntry {
     changes: Vec<Change>,
     session: i64,
+    user_id: String,
+    change_id: u64,
+    // Changes reverting this write, based on the revision it produced
+    inverse: Vec<Change>,
 }
+impl ChangeEntry {
+    fn to_wal_record(&self, epoch: i64) -> WalRecord {
+        WalRecord {
+            epoch,
+            session: self.session,
+            change_id: self.change_id,
+            changes: self
+                .changes
+                .iter()
+                .map(|c| docs::Change {
+                    change: Some(c.clone()),
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct DocCacheEntry {
-    changes: Vec<ChangeEntry>,
+    // Current content with every change applied
+    content: Rope,
+    history: VecDeque<ChangeEntry>,
+    // Last cursor of each session, shifted by every write
+    cursors: HashMap<i64, DocEventCursor>,
     last_update: SystemTime,
     change_id: u64,
+    // Change id of the content last saved to the database
+    saved_change_id: u64,
+    last_revision: Option<SystemTime>,
+    undo_stacks: HashMap<i64, UndoStack>,
+    // Load of the text in the cache, written in its WAL records as change ids start over on each load
+    epoch: i64,
+}
+
+/// Groups of writes a session can undo or redo, identified by their change ids
+/// Consecutive writes of a session are undone together until the session stops writing for a while
+#[derive(Debug, Clone, Default)]
+struct UndoStack {
+    undo: Vec<Vec<u64>>,
+    redo: Vec<Vec<u64>>,
+    // Time of the last write of the session
+    last_write: Option<Instant>,
+}
+
+impl UndoStack {
+    /// Add a write to the last group if it directly follows it less than `group_delay` after the previous write
+    fn push_write(&mut self, change_id: u64, now: Instant, group_delay: Duration) {
+        self.redo.clear();
+        let recent =
+            matches!(self.last_write, Some(last) if now.duration_since(last) < group_delay);
+        self.last_write = Some(now);
+        match self.undo.last_mut() {
+            Some(group) if recent && group.last() == Some(&(change_id - 1)) => {
+                group.push(change_id)
+            }
+            _ => self.undo.push(vec![change_id]),
+        }
+    }
+}
+
+impl DocCacheEntry {
+    /// Get every change applied by other sessions since the `change_id` revision
+    fn changes_since(
+        &self,
+        key: DocKey,
+        session: i64,
+        change_id: u64,
+    ) -> Result<Vec<Change>, Status> {
+        if change_id > self.change_id {
+            return Err(Status::invalid_argument(format!(
+                "Unknown change id {change_id} for {key:?}"
+            )));
+        }
+        if self
+            .history
+            .front()
+            .map(|entry| entry.change_id > change_id + 1)
+            .unwrap_or(false)
+        {
+            return Err(Status::out_of_range(format!(
+                "Change id {change_id} for {key:?} is too old"
+            )));
+        }
+        Ok(self
+            .history
+            .iter()
+            .filter(|entry| entry.change_id > change_id && entry.session != session)
+            .flat_map(|entry| entry.changes.iter().cloned())
+            .collect())
+    }
+
+    /// Check that a write of a session is based on the revision of its last write
+    /// Writes sent before the previous one is acknowledged would be transformed against
+    /// changes already rebased past the session's own writes, so only one write per session can be in flight
+    fn check_write_base(&self, key: DocKey, session: i64, change_id: u64) -> Result<(), Status> {
+        match self.history.iter().rev().find(|entry| entry.session == session) {
+            Some(last) if last.change_id > change_id => Err(Status::failed_precondition(format!(
+                "Write on change id {change_id} for {key:?} is older than the last write {} of the session",
+                last.change_id
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of writes applied since the last save
+    fn pending_changes(&self) -> u64 {
+        self.change_id - self.saved_change_id
+    }
+}
+
+/// Identify a text edited collaboratively through the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DocKey {
+    Doc(i32),
+    Sheet(i32),
+    NodeContent(i32),
+    NodeSummary(i32),
+}
+
+impl DocKey {
+    pub fn id(&self) -> i32 {
+        match self {
+            DocKey::Doc(id)
+            | DocKey::Sheet(id)
+            | DocKey::NodeContent(id)
+            | DocKey::NodeSummary(id) => *id,
+        }
+    }
+
+    /// Load the stored content of the text
+    async fn get_content(&self) -> Result<String, Status> {
+        match self {
+            DocKey::Doc(id) => queries::get_document_content(id).await,
+            DocKey::Sheet(id) => queries::get_sheet_content(id).await,
+            DocKey::NodeContent(id) => queries::get_node_content(id).await,
+            DocKey::NodeSummary(id) => queries::get_node_summary(id).await,
+        }
+    }
+
+    /// Name of the text, used for its WAL segment and checkpoint
+    pub fn name(&self) -> String {
+        match self {
+            DocKey::Doc(id) => format!("doc-{id}"),
+            DocKey::Sheet(id) => format!("sheet-{id}"),
+            DocKey::NodeContent(id) => format!("node_content-{id}"),
+            DocKey::NodeSummary(id) => format!("node_summary-{id}"),
+        }
+    }
+}
+
+/// Records of a WAL segment that are not included in the saved content, given its checkpoint
+fn unsaved_records(records: Vec<WalRecord>, checkpoint: Option<(i64, u64)>) -> Vec<WalRecord> {
+    records
+        .into_iter()
+        .filter(|record| !matches!(checkpoint, Some(saved) if (record.epoch, record.change_id) <= saved))
+        .collect()
+}
+
+/// Apply a single change to a content
+fn apply_change(content: &mut Rope, change: &Change) -> Result<(), Status> {
+    match change {
+        Change::Insert(ref insert) => text::insert(content, insert.position, &insert.content),
+        Change::Remove(ref remove) => text::remove(content, remove.position, remove.size),
+        Change::Replace(ref replace) => {
+            *content = Rope::from_str(&replace.content);
+            Ok(())
+        }
+    }
+}
+
+/// Get the change reverting `change` once it is applied to `content`
+fn invert_change(content: &Rope, change: &Change) -> Result<Change, Status> {
+    Ok(match change {
+        Change::Insert(insert) => Change::Remove(Remove {
+            position: insert.position,
+            size: text::utf16_len(&insert.content),
+        }),
+        Change::Remove(remove) => Change::Insert(Insert {
+            position: remove.position,
+            content: text::slice(content, remove.position, remove.size)?,
+        }),
+        Change::Replace(_) => Change::Replace(Replace {
+            content: content.to_string(),
+        }),
+    })
+}
+
+/// Wait for a write to be logged
+/// The write is already applied to the cache, a logging error is reported but does not fail it
+async fn wait_logged(key: DocKey, logged: impl Future<Output = Result<(), Status>>) {
+    if let Err(e) = logged.await {
+        log::error!("Error while logging write to {:?}: {}", key, e);
+    }
+}
+
+/// A document edited through the CRDT engine
+#[derive(Debug, Clone)]
+struct CrdtCacheEntry {
+    doc: CrdtDoc,
+    last_update: SystemTime,
+    // Number of updates merged since the last save
+    pending_updates: usize,
+    // User of the last merged update, the author of the next revision
+    last_user_id: Option<String>,
+    last_revision: Option<SystemTime>,
 }
 
 #[derive(Debug, Clone)]
 pub struct DocsCache {
-    doc_cache: DashMap<i32, DocCacheEntry>,
+    doc_cache: DashMap<DocKey, DocCacheEntry>,
+    // Documents opened with the CRDT engine, a document is never in both caches
+    crdt_cache: DashMap<i32, CrdtCacheEntry>,
+    // Every write is logged before being applied to the cache
+    wal: Wal,
+    // Minimum delay between two revisions of a document
+    revision_interval: Duration,
+    // Fill the empty summary of a node when its content is saved
+    auto_summary: bool,
+    // Maximum delay between two writes of a session undone together
+    undo_group_delay: Duration,
 }
 
 impl DocsCache {
     pub fn new_arc() -> Arc<Self> {
         let inst = Arc::new(Self {
             doc_cache: DashMap::new(),
+            crdt_cache: DashMap::new(),
+            wal: Wal::from_env(),
+            revision_interval: Duration::from_secs(
+                std::env::var("REVISION_INTERVAL")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+            ),
+            auto_summary: std::env::var("AUTO_SUMMARY")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            undo_group_delay: Duration::from_millis(
+                std::env::var("UNDO_GROUP_DELAY")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(1000),
+            ),
         });
 
         // Start interval update task
@@ -64,113 +310,657 @@ impl DocsCache {
                 self.doc_cache
                     .iter_mut()
                     .filter(|entry| {
-                        entry.changes.len() > 0
+                        entry.pending_changes() > 0
                             && (entry.last_update.elapsed().unwrap_or_default().as_secs() > 30
-                                || entry.changes.len() > 100)
+                                || entry.pending_changes() > 100)
                     })
-                    .map(|entry| self.apply_doc_changes(entry.key().clone())),
+                    .map(|entry| self.apply_doc_changes(*entry.key())),
             )
             .await;
-			for r in res {
-				if let Err(e) = r {
-					log::error!("Error while updating document: {}", e);
-				}
-			}
+            for r in res {
+                if let Err(e) = r {
+                    log::error!("Error while updating document: {}", e);
+                }
+            }
+            let res = join_all(
+                self.crdt_cache
+                    .iter()
+                    .filter(|entry| {
+                        entry.pending_updates > 0
+                            && (entry.last_update.elapsed().unwrap_or_default().as_secs() > 30
+                                || entry.pending_updates > 100)
+                    })
+                    .map(|entry| self.apply_crdt_doc_changes(*entry.key())),
+            )
+            .await;
+            for r in res {
+                if let Err(e) = r {
+                    log::error!("Error while updating CRDT document: {}", e);
+                }
+            }
         }
     }
 
-    /// Build the document content from the list of changes
-    async fn build_doc_changes(&self, id: i32) -> Result<String, Status> {
-        let mut content = queries::get_document_content(&id).await?;
-        let entry = self
-            .doc_cache
-            .get(&id)
-            .ok_or(Status::data_loss("Document not found"))?;
-        for change_entry in entry.changes.iter() {
-            for change in change_entry.changes.iter() {
-				log::debug!("Applying change to {id}: {:?}", change);
-                match change {
-                    Change::Insert(ref insert) => {
-						if insert.position as usize > content.len() || insert.position < 0 {
-							return Err(Status::data_loss("Invalid insert position"));
-						}
-                        content.insert_str(insert.position as usize, &insert.content);
-                    }
-                    Change::Remove(ref remove) => {
-                        content = content.to_remove_range(
-                            remove.position as usize,
-                            (remove.position + remove.size) as usize,
-                        );
-                    }
-                    Change::Replace(ref replace) => {
-                        content = replace.content.clone();
-                    }
-                }
+    /// Save the current content of a document if it changed since the last save
+    /// A revision of documents is stored if the last one is older than the revision interval
+    async fn apply_doc_changes(&self, key: DocKey) -> Result<(), Status> {
+        let (content, change_id, epoch, author_id, store_revision) = {
+            let entry = self
+                .doc_cache
+                .get(&key)
+                .ok_or(Status::data_loss("Document not found"))?;
+            if entry.pending_changes() == 0 {
+                return Ok(());
+            }
+            let store_revision =
+                matches!(key, DocKey::Doc(_)) && self.revision_due(entry.last_revision);
+            (
+                entry.content.to_string(),
+                entry.change_id,
+                entry.epoch,
+                entry.history.back().map(|change| change.user_id.clone()),
+                store_revision,
+            )
+        };
+        log::info!("Applying changes to {:?}", key);
+        queries::save_text_content(key, &content, epoch, change_id).await?;
+        if store_revision {
+            queries::create_revision(&key.id(), &content, &author_id).await?;
+        }
+        let truncated = self.doc_cache.get_mut(&key).map(|mut entry| {
+            entry.saved_change_id = entry.saved_change_id.max(change_id);
+            if store_revision {
+                entry.last_revision = Some(SystemTime::now());
             }
+            // Keep the writes applied while saving in the log
+            let records: Vec<WalRecord> = entry
+                .history
+                .iter()
+                .filter(|change| change.change_id > entry.saved_change_id)
+                .map(|change| change.to_wal_record(entry.epoch))
+                .collect();
+            // Queued while the entry is locked so that it is ordered with the writes
+            self.wal.truncate(key, records)
+        });
+        if let Some(truncated) = truncated {
+            truncated.await?;
         }
-        Ok::<String, Status>(content)
+        if let (DocKey::NodeContent(node_id), true) = (key, self.auto_summary) {
+            if let Err(e) = self.auto_summarize(node_id, &content).await {
+                log::error!("Error while summarizing node {}: {}", node_id, e);
+            }
+        }
+        Ok(())
     }
 
-    async fn apply_doc_changes(&self, id: i32) -> Result<(), Status> {
-		log::info!("Applying changes to doc {}", id);
-        let content = self.build_doc_changes(id).await?;
+    /// Summarize the content of a node if its summary is empty and not being edited
+    async fn auto_summarize(&self, node_id: i32, content: &str) -> Result<(), Status> {
+        if self.doc_cache.contains_key(&DocKey::NodeSummary(node_id))
+            || !queries::get_node_summary(&node_id).await?.trim().is_empty()
+        {
+            return Ok(());
+        }
+        let summary = summarizer::summarize(content, summarizer::DEFAULT_SENTENCES);
+        if summary.is_empty() {
+            return Ok(());
+        }
+        queries::set_node_summary(&node_id, &summary).await
+    }
+
+    /// Apply the writes left in the log by a previous run and save the documents
+    pub async fn replay_wal(&self) {
+        let segments = match self.wal.read_segments() {
+            Ok(segments) => segments,
+            Err(e) => {
+                log::error!("Error while reading WAL: {}", e);
+                return;
+            }
+        };
+        for (key, records) in segments {
+            log::info!("Replaying {} WAL records of {:?}", records.len(), key);
+            if let Err(e) = self.replay_segment(key, records).await {
+                log::error!("Error while replaying WAL of {:?}: {}", key, e);
+            }
+        }
+    }
 
+    /// Apply the records of a segment that were not saved before the previous run stopped
+    async fn replay_segment(&self, key: DocKey, records: Vec<WalRecord>) -> Result<(), Status> {
+        let checkpoint = queries::get_wal_checkpoint(key).await?;
+        let records = unsaved_records(records, checkpoint);
+        if let Some((epoch, change_id)) = records.iter().map(|r| (r.epoch, r.change_id)).max() {
+            let mut content = Rope::from_str(&key.get_content().await?);
+            for change in records
+                .iter()
+                .flat_map(|record| record.changes.iter())
+                .filter_map(|c| c.change.as_ref())
+            {
+                apply_change(&mut content, change)?;
+            }
+            queries::save_text_content(key, &content.to_string(), epoch, change_id).await?;
+        }
+        self.wal.truncate(key, Vec::new()).await
+    }
+
+    /// Check if a revision must be stored given the time of the last one
+    fn revision_due(&self, last_revision: Option<SystemTime>) -> bool {
+        last_revision
+            .and_then(|last| last.elapsed().ok())
+            .unwrap_or(Duration::MAX)
+            >= self.revision_interval
+    }
+
+    /// Save the plain text snapshot of a CRDT document
+    /// A revision is stored like for other documents if the last one is older than the revision interval
+    async fn apply_crdt_doc_changes(&self, id: i32) -> Result<(), Status> {
+        log::info!("Applying CRDT changes to doc {}", id);
+        let (content, flushed, author_id, store_revision) = {
+            let entry = self
+                .crdt_cache
+                .get(&id)
+                .ok_or(Status::data_loss("Document not found"))?;
+            (
+                entry.doc.content(),
+                entry.pending_updates,
+                entry.last_user_id.clone(),
+                self.revision_due(entry.last_revision),
+            )
+        };
         queries::set_doc_content(&id, &content).await?;
-		self.doc_cache.get_mut(&id).unwrap().changes.clear();
+        if store_revision {
+            queries::create_revision(&id, &content, &author_id).await?;
+        }
+        if let Some(mut entry) = self.crdt_cache.get_mut(&id) {
+            entry.pending_updates -= flushed;
+            if store_revision {
+                entry.last_revision = Some(SystemTime::now());
+            }
+        }
         Ok(())
     }
 
-    /// Register a document to the cache and return the content and change id
-    /// If the document is already in the cache, it will return the cached content and change id
-    pub async fn register_doc(&self, doc_id: i32) -> Result<(String, u64), Status> {
-        if !self.doc_cache.contains_key(&doc_id) {
-            self.doc_cache.insert(
-                doc_id,
-                DocCacheEntry {
-                    changes: Vec::new(),
+    /// Register a document or a sheet to the cache and return the content and change id
+    /// If it is already in the cache, it will return the cached content and change id
+    pub async fn register_doc(&self, key: DocKey) -> Result<(String, u64), Status> {
+        if matches!(key, DocKey::Doc(id) if self.crdt_cache.contains_key(&id)) {
+            return Err(Status::failed_precondition(
+                "Document is opened with the CRDT engine",
+            ));
+        }
+        if !self.doc_cache.contains_key(&key) {
+            let content = key.get_content().await?;
+            let epoch = get_snowflake().await;
+            self.doc_cache.entry(key).or_insert_with(|| DocCacheEntry {
+                content: Rope::from_str(&content),
+                history: VecDeque::new(),
+                cursors: HashMap::new(),
+                last_update: SystemTime::now(),
+                change_id: 0,
+                saved_change_id: 0,
+                last_revision: None,
+                undo_stacks: HashMap::new(),
+                epoch,
+            });
+        }
+        let entry = self.doc_cache.get(&key).unwrap();
+        Ok((entry.content.to_string(), entry.change_id))
+    }
+
+    /// Register a document to the CRDT cache and return its content and encoded CRDT state
+    /// The CRDT state is created from the stored content if the document is not in the cache
+    pub async fn register_crdt_doc(&self, doc_id: i32) -> Result<(String, Vec<u8>), Status> {
+        if self.doc_cache.contains_key(&DocKey::Doc(doc_id)) {
+            return Err(Status::failed_precondition(
+                "Document is opened with the change log engine",
+            ));
+        }
+        if !self.crdt_cache.contains_key(&doc_id) {
+            let content = queries::get_document_content(&doc_id).await?;
+            self.crdt_cache
+                .entry(doc_id)
+                .or_insert_with(|| CrdtCacheEntry {
+                    doc: CrdtDoc::new(&content),
                     last_update: SystemTime::now(),
-                    change_id: 0,
-                },
-            );
+                    pending_updates: 0,
+                    last_user_id: None,
+                    last_revision: None,
+                });
         }
-        let content = self.build_doc_changes(doc_id).await?;
-        Ok((content, self.doc_cache.get(&doc_id).unwrap().change_id))
+        let entry = self.crdt_cache.get(&doc_id).unwrap();
+        Ok((entry.doc.content(), entry.doc.encode_state()))
     }
 
-	/// Remove a document from the cache
-	/// Apply all registered changes to the document
-    pub async fn remove_doc(&self, doc_id: i32) -> Result<(), Status> {
-        self.apply_doc_changes(doc_id).await?;
-        self.doc_cache.remove(&doc_id);
+    /// Remove a document from the cache
+    /// Apply all registered changes to the document
+    pub async fn remove_doc(&self, key: DocKey) -> Result<(), Status> {
+        if let DocKey::Doc(doc_id) = key {
+            if self.crdt_cache.contains_key(&doc_id) {
+                self.apply_crdt_doc_changes(doc_id).await?;
+                self.crdt_cache.remove(&doc_id);
+                return Ok(());
+            }
+        }
+        self.apply_doc_changes(key).await?;
+        self.doc_cache.remove(&key);
         Ok(())
     }
 
-	pub fn clear_doc_cache(&self, doc_id: i32) {
-		self.doc_cache.remove(&doc_id);
-	}
-    pub fn update_doc(&self, session: i64, doc_id: i32, mut changes: Vec<Change>, change_id: u64) {
-        if let Some(mut doc) = self.doc_cache.get_mut(&doc_id) {
-            if doc
-                .changes
-                .last_mut()
-                .map(|last| last.session == session)
-                .unwrap_or(false)
-            {
-                doc.changes.last_mut().unwrap().changes.append(&mut changes);
+    pub async fn clear_doc_cache(&self, key: DocKey) {
+        self.doc_cache.remove(&key);
+        if let Err(e) = self.wal.truncate(key, Vec::new()).await {
+            log::error!("Error while removing WAL of {:?}: {}", key, e);
+        }
+        if let DocKey::Doc(doc_id) = key {
+            self.crdt_cache.remove(&doc_id);
+        }
+    }
+    /// Apply changes made by a session on the `change_id` revision of a document.
+    /// The changes are transformed against every change applied by other sessions since this revision,
+    /// it must not be older than the last write of the session.
+    /// Return the transformed changes and the new change id of the document.
+    pub async fn update_doc(
+        &self,
+        session: i64,
+        user_id: &str,
+        key: DocKey,
+        changes: Vec<Change>,
+        change_id: u64,
+    ) -> Result<(Vec<Change>, u64), Status> {
+        let (changes, change_id, logged) = {
+            let mut doc = self
+                .doc_cache
+                .get_mut(&key)
+                .ok_or(Status::not_found("Document not found"))?;
+            ot::validate(&changes)?;
+            doc.check_write_base(key, session, change_id)?;
+            let concurrent = doc.changes_since(key, session, change_id)?;
+            let changes = ot::transform(changes, concurrent);
+            let (change_id, logged) =
+                self.apply_write(key, &mut doc, session, user_id, &changes)?;
+            doc.undo_stacks.entry(session).or_default().push_write(
+                change_id,
+                Instant::now(),
+                self.undo_group_delay,
+            );
+            (changes, change_id, logged)
+        };
+        wait_logged(key, logged).await;
+        Ok((changes, change_id))
+    }
+
+    /// Replace the content of a cached text with a write of the server
+    /// Return `None` if the text is not cached
+    pub async fn replace_doc(
+        &self,
+        user_id: &str,
+        key: DocKey,
+        content: String,
+    ) -> Result<Option<(Vec<Change>, u64)>, Status> {
+        let changes = vec![Change::Replace(Replace { content })];
+        let (change_id, logged) = {
+            let Some(mut doc) = self.doc_cache.get_mut(&key) else {
+                return Ok(None);
+            };
+            self.apply_write(key, &mut doc, SERVER_SESSION, user_id, &changes)?
+        };
+        wait_logged(key, logged).await;
+        Ok(Some((changes, change_id)))
+    }
+
+    /// Revert the last group of writes of a session
+    /// Return `None` if there is nothing to undo
+    pub async fn undo(
+        &self,
+        session: i64,
+        user_id: &str,
+        key: DocKey,
+    ) -> Result<Option<(Vec<Change>, u64)>, Status> {
+        self.revert(session, user_id, key, false).await
+    }
+
+    /// Revert the last undo of a session
+    /// Return `None` if there is nothing to redo
+    pub async fn redo(
+        &self,
+        session: i64,
+        user_id: &str,
+        key: DocKey,
+    ) -> Result<Option<(Vec<Change>, u64)>, Status> {
+        self.revert(session, user_id, key, true).await
+    }
+
+    /// Revert the group of writes on top of the undo or redo stack of a session
+    /// The reverting write is pushed on the other stack
+    async fn revert(
+        &self,
+        session: i64,
+        user_id: &str,
+        key: DocKey,
+        redo: bool,
+    ) -> Result<Option<(Vec<Change>, u64)>, Status> {
+        let (changes, change_id, logged) = {
+            let mut doc = self
+                .doc_cache
+                .get_mut(&key)
+                .ok_or(Status::not_found("Document not found"))?;
+            let group = match doc.undo_stacks.get_mut(&session) {
+                Some(stack) if redo => stack.redo.pop(),
+                Some(stack) => stack.undo.pop(),
+                None => None,
+            };
+            let Some(group) = group else {
+                return Ok(None);
+            };
+            // Each inverse is rebased on every write applied after it, including the inverses already computed
+            let mut changes: Vec<Change> = Vec::new();
+            for change_id in group.iter().rev() {
+                let index = doc
+                    .history
+                    .iter()
+                    .position(|entry| entry.change_id == *change_id)
+                    .ok_or(Status::failed_precondition(
+                        "Change is too old to be reverted",
+                    ))?;
+                let applied: Vec<Change> = doc
+                    .history
+                    .iter()
+                    .skip(index + 1)
+                    .flat_map(|entry| entry.changes.iter().cloned())
+                    .chain(changes.iter().cloned())
+                    .collect();
+                changes.extend(ot::transform(doc.history[index].inverse.clone(), applied));
+            }
+            let (change_id, logged) =
+                self.apply_write(key, &mut doc, session, user_id, &changes)?;
+            let stack = doc.undo_stacks.entry(session).or_default();
+            if redo {
+                stack.undo.push(vec![change_id]);
             } else {
-                doc.changes.push(ChangeEntry { changes, session });
+                stack.redo.push(vec![change_id]);
+            }
+            (changes, change_id, logged)
+        };
+        wait_logged(key, logged).await;
+        Ok(Some((changes, change_id)))
+    }
+
+    /// Apply changes made on the current revision of a document, log them and add them to its history
+    /// Return the new change id and a future resolving once the write is logged,
+    /// it must be awaited after the entry is unlocked
+    fn apply_write(
+        &self,
+        key: DocKey,
+        doc: &mut DocCacheEntry,
+        session: i64,
+        user_id: &str,
+        changes: &[Change],
+    ) -> Result<(u64, impl Future<Output = Result<(), Status>>), Status> {
+        // Changes are applied to a copy so that an invalid write leaves the content untouched
+        let mut content = doc.content.clone();
+        let mut inverse = Vec::with_capacity(changes.len());
+        for change in changes.iter() {
+            log::debug!("Applying change to {:?}: {:?}", key, change);
+            inverse.push(invert_change(&content, change)?);
+            apply_change(&mut content, change)?;
+        }
+        inverse.reverse();
+        let entry = ChangeEntry {
+            changes: changes.to_vec(),
+            session,
+            user_id: user_id.to_string(),
+            change_id: doc.change_id + 1,
+            inverse,
+        };
+        let logged = self.wal.append(key, entry.to_wal_record(doc.epoch));
+
+        doc.content = content;
+        for cursor in doc.cursors.values_mut() {
+            ot::transform_cursor(cursor, changes);
+        }
+        doc.change_id = entry.change_id;
+        if doc.history.len() >= HISTORY_SIZE {
+            doc.history.pop_front();
+        }
+        doc.history.push_back(entry);
+        doc.last_update = SystemTime::now();
+        Ok((doc.change_id, logged))
+    }
+
+    /// Get the current content of a text, from the cache if it is opened
+    pub async fn get_content(&self, key: DocKey) -> Result<String, Status> {
+        match self.get_cached_content(key) {
+            Some(content) => Ok(content),
+            None => key.get_content().await,
+        }
+    }
+
+    /// Get the content of a text if it is opened
+    pub fn get_cached_content(&self, key: DocKey) -> Option<String> {
+        if let DocKey::Doc(doc_id) = key {
+            if let Some(entry) = self.crdt_cache.get(&doc_id) {
+                return Some(entry.doc.content());
             }
-            doc.last_update = SystemTime::now();
-            doc.change_id += 1;
-        } else {
-            warn!("Trying to modify a doc not found: {doc_id}!");
         }
+        self.doc_cache.get(&key).map(|doc| doc.content.to_string())
+    }
+
+    /// Get the current change id of a cached document
+    pub fn get_change_id(&self, key: DocKey) -> Option<u64> {
+        self.doc_cache.get(&key).map(|doc| doc.change_id)
+    }
+
+    /// Set the cursor of a session, its positions are transformed from the `change_id` revision
+    /// Return the cursor with its positions in the current revision
+    pub fn update_cursor(
+        &self,
+        key: DocKey,
+        mut cursor: DocEventCursor,
+        change_id: u64,
+    ) -> Result<DocEventCursor, Status> {
+        let mut doc = self
+            .doc_cache
+            .get_mut(&key)
+            .ok_or(Status::not_found("Document not found"))?;
+        ot::validate_cursor(&cursor)?;
+        let concurrent = doc.changes_since(key, cursor.session_id, change_id)?;
+        ot::transform_cursor(&mut cursor, &concurrent);
+        doc.cursors.insert(cursor.session_id, cursor.clone());
+        Ok(cursor)
+    }
+
+    /// Get the cursors of every session on a document
+    pub fn get_cursors(&self, key: DocKey) -> Vec<DocEventCursor> {
+        self.doc_cache
+            .get(&key)
+            .map(|doc| doc.cursors.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Remove the cursor and the undo history of a session
+    pub fn remove_session(&self, key: DocKey, session: i64) {
+        if let Some(mut doc) = self.doc_cache.get_mut(&key) {
+            doc.cursors.remove(&session);
+            doc.undo_stacks.remove(&session);
+        }
+    }
+
+    /// Merge a CRDT update sent by a client into a document
+    pub fn update_crdt_doc(&self, doc_id: i32, user_id: &str, update: &[u8]) -> Result<(), Status> {
+        let mut entry = self
+            .crdt_cache
+            .get_mut(&doc_id)
+            .ok_or(Status::not_found("Document not found"))?;
+        entry.doc.apply_update(update)?;
+        entry.pending_updates += 1;
+        entry.last_user_id = Some(user_id.to_string());
+        entry.last_update = SystemTime::now();
+        Ok(())
+    }
+
+    /// Check if a document is opened with the CRDT engine
+    pub fn is_crdt_doc(&self, doc_id: i32) -> bool {
+        self.crdt_cache.contains_key(&doc_id)
+    }
+
+    /// Replace the content of a document opened with the CRDT engine
+    /// Return the update to broadcast, or `None` if the document is not in the CRDT cache
+    pub fn replace_crdt_doc(&self, doc_id: i32, user_id: &str, content: &str) -> Option<Vec<u8>> {
+        let mut entry = self.crdt_cache.get_mut(&doc_id)?;
+        let update = entry.doc.replace_content(content);
+        entry.pending_updates += 1;
+        entry.last_user_id = Some(user_id.to_string());
+        entry.last_update = SystemTime::now();
+        Some(update)
+    }
+
+    /// Get the CRDT updates missing from a client given its state vector
+    pub fn sync_crdt_doc(&self, doc_id: i32, state_vector: &[u8]) -> Result<Vec<u8>, Status> {
+        self.crdt_cache
+            .get(&doc_id)
+            .ok_or(Status::not_found("Document not found"))?
+            .doc
+            .encode_diff(state_vector)
+    }
+
+    /// Get every write applied to a document after the `change_id` revision
+    /// The current content is sent instead if the history doesn't go back to this revision
+    pub fn get_changes_since(
+        &self,
+        key: DocKey,
+        change_id: u64,
+    ) -> Result<ChangesSinceResponse, Status> {
+        let doc = self
+            .doc_cache
+            .get(&key)
+            .ok_or(Status::not_found("Document not found"))?;
+        let compacted = doc
+            .history
+            .front()
+            .map(|entry| entry.change_id > change_id + 1)
+            .unwrap_or(doc.change_id > change_id);
+        if compacted || change_id > doc.change_id {
+            return Ok(ChangesSinceResponse {
+                writes: vec![],
+                snapshot: true,
+                content: doc.content.to_string(),
+                change_id: doc.change_id,
+            });
+        }
+        let writes = doc
+            .history
+            .iter()
+            .filter(|entry| entry.change_id > change_id)
+            .map(|entry| DocEventWrite {
+                id: key.id(),
+                user_id: entry.user_id.clone(),
+                session_id: entry.session,
+                changes: entry
+                    .changes
+                    .iter()
+                    .map(|c| docs::Change {
+                        change: Some(c.clone()),
+                    })
+                    .collect(),
+                change_id: entry.change_id,
+            })
+            .collect();
+        Ok(ChangesSinceResponse {
+            writes,
+            snapshot: false,
+            content: String::new(),
+            change_id: doc.change_id,
+        })
+    }
+
+    // Get a crc of the current content and compare it with client
+    pub fn crc_check(&self, key: DocKey, crc: u32) -> Result<bool, Status> {
+        if let DocKey::Doc(doc_id) = key {
+            if let Some(entry) = self.crdt_cache.get(&doc_id) {
+                return Ok(crc32fast::hash(entry.doc.content().as_bytes()) == crc);
+            }
+        }
+        let entry = self
+            .doc_cache
+            .get(&key)
+            .ok_or(Status::not_found("Document not found"))?;
+        Ok(text::crc(&entry.content) == crc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(writes: &[(i64, u64)]) -> DocCacheEntry {
+        DocCacheEntry {
+            content: Rope::new(),
+            history: writes
+                .iter()
+                .map(|(session, change_id)| ChangeEntry {
+                    changes: vec![],
+                    session: *session,
+                    user_id: String::new(),
+                    change_id: *change_id,
+                    inverse: vec![],
+                })
+                .collect(),
+            cursors: HashMap::new(),
+            last_update: SystemTime::now(),
+            change_id: writes.last().map(|(_, id)| *id).unwrap_or(0),
+            saved_change_id: 0,
+            last_revision: None,
+            undo_stacks: HashMap::new(),
+            epoch: 1,
+        }
+    }
+
+    #[test]
+    fn write_must_be_based_on_the_last_write_of_its_session() {
+        let key = DocKey::Doc(1);
+        let doc = entry(&[(2, 6), (1, 7), (2, 8)]);
+        // A second write of session 1 sent before its write 7 was acknowledged
+        let err = doc.check_write_base(key, 1, 5).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert!(doc.check_write_base(key, 1, 7).is_ok());
+        assert!(doc.check_write_base(key, 2, 8).is_ok());
+        assert!(doc.check_write_base(key, 3, 5).is_ok());
+    }
+
+    fn record(epoch: i64, change_id: u64) -> WalRecord {
+        WalRecord {
+            session: 1,
+            change_id,
+            changes: vec![],
+            epoch,
+        }
+    }
+
+    #[test]
+    fn replay_skips_records_included_in_the_saved_content() {
+        let records = vec![record(1, 1), record(1, 2), record(2, 1), record(2, 2)];
+        let ids = |records: Vec<WalRecord>| -> Vec<(i64, u64)> {
+            records.iter().map(|r| (r.epoch, r.change_id)).collect()
+        };
+        assert_eq!(ids(unsaved_records(records.clone(), None)).len(), 4);
+        assert_eq!(
+            ids(unsaved_records(records.clone(), Some((2, 1)))),
+            vec![(2, 2)]
+        );
+        assert!(unsaved_records(records, Some((2, 2))).is_empty());
     }
 
-    // Apply change to the content and get a crc and compare it with client
-    pub async fn crc_check(&self, doc_id: i32, crc: u32) -> Result<bool, Status> {
-        let content = self.build_doc_changes(doc_id).await?;
-        let hash = crc32fast::hash(content.as_bytes());
-        Ok(hash == crc)
+    #[test]
+    fn writes_are_grouped_until_the_session_pauses() {
+        let delay = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut stack = UndoStack::default();
+        stack.push_write(1, start, delay);
+        stack.push_write(2, start + Duration::from_millis(200), delay);
+        // Another session wrote change 3
+        stack.push_write(4, start + Duration::from_millis(400), delay);
+        stack.push_write(5, start + Duration::from_millis(600), delay);
+        // The session paused before its next write
+        stack.push_write(6, start + Duration::from_secs(3), delay);
+        assert_eq!(stack.undo, vec![vec![1, 2], vec![4, 5], vec![6]]);
     }
 }
@@ -0,0 +1,95 @@
+//! Sequence CRDT state of a document, compatible with Yjs clients
+//! Updates and state vectors are exchanged with the Yjs v1 encoding
+use tonic::Status;
+use yrs::{
+    updates::decoder::Decode, Doc, GetString, OffsetKind, Options, ReadTxn, StateVector, Text,
+    Transact, Update,
+};
+
+/// Name of the shared text holding the document content
+const TEXT_NAME: &str = "content";
+
+#[derive(Debug, Clone)]
+pub struct CrdtDoc {
+    doc: Doc,
+}
+
+impl CrdtDoc {
+    /// Create the CRDT state of a document from its stored content
+    /// The content is inserted with a random client id, so that the items of each load
+    /// never reuse the ids of items created by a previous load that clients may still hold
+    pub fn new(content: &str) -> Self {
+        let doc = Doc::with_options(Options {
+            offset_kind: OffsetKind::Utf16,
+            ..Options::default()
+        });
+        let text = doc.get_or_insert_text(TEXT_NAME);
+        if !content.is_empty() {
+            text.insert(&mut doc.transact_mut(), 0, content);
+        }
+        Self { doc }
+    }
+
+    /// Merge an update sent by a client
+    pub fn apply_update(&self, update: &[u8]) -> Result<(), Status> {
+        let update = Update::decode_v1(update)
+            .map_err(|e| Status::invalid_argument(format!("Invalid CRDT update: {}", e)))?;
+        self.doc.transact_mut().apply_update(update);
+        Ok(())
+    }
+
+    /// Encode the whole state of the document as a single update
+    pub fn encode_state(&self) -> Vec<u8> {
+        self.doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default())
+    }
+
+    /// Encode the updates missing from a client given its state vector
+    pub fn encode_diff(&self, state_vector: &[u8]) -> Result<Vec<u8>, Status> {
+        let state_vector = StateVector::decode_v1(state_vector)
+            .map_err(|e| Status::invalid_argument(format!("Invalid state vector: {}", e)))?;
+        Ok(self.doc.transact().encode_diff_v1(&state_vector))
+    }
+
+    /// Replace the whole content and return the update to send to the clients
+    pub fn replace_content(&self, content: &str) -> Vec<u8> {
+        let text = self.doc.get_or_insert_text(TEXT_NAME);
+        let mut txn = self.doc.transact_mut();
+        let len = text.len(&txn);
+        text.remove_range(&mut txn, 0, len);
+        text.insert(&mut txn, 0, content);
+        txn.encode_update_v1()
+    }
+
+    /// Plain text snapshot of the document
+    pub fn content(&self) -> String {
+        let text = self.doc.get_or_insert_text(TEXT_NAME);
+        let txn = self.doc.transact();
+        text.get_string(&txn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_load_uses_its_own_client_id() {
+        let first = CrdtDoc::new("hello");
+        let second = CrdtDoc::new("hello");
+        assert_ne!(first.doc.client_id(), second.doc.client_id());
+        assert_eq!(first.content(), second.content());
+    }
+
+    #[test]
+    fn replace_content_update_is_merged_by_clients() {
+        let server = CrdtDoc::new("hello");
+        let client = CrdtDoc::new("");
+        client.apply_update(&server.encode_state()).unwrap();
+        let update = server.replace_content("world");
+        assert_eq!(server.content(), "world");
+        client.apply_update(&update).unwrap();
+        assert_eq!(client.content(), "world");
+    }
+}
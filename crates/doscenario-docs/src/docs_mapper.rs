@@ -1,33 +1,74 @@
-use doscenario_models::{document, sheet};
+use doscenario_models::{document, document_revision, sea_orm_active_enums, sheet};
 
-use crate::docs::{OpenDocResponse, SheetEntity};
+use crate::docs::{OpenDocResponse, OpenSheetResponse, ProjectRole, RevisionEntity, SheetEntity};
 
-impl Into<OpenDocResponse> for document::DocumentModel {
-    fn into(self) -> OpenDocResponse {
+impl From<document::DocumentModel> for OpenDocResponse {
+    fn from(doc: document::DocumentModel) -> Self {
         OpenDocResponse {
-            color: self.color.unwrap_or_default(),
-            created_date: self.created_date.to_string(),
-            last_editing: self.last_editing.to_string(),
-            id: self.id,
-            title: self.title,
-            uid: self.uid,
-            content: self.content.unwrap_or_default(),
+            color: doc.color.unwrap_or_default(),
+            created_date: doc.created_date.to_string(),
+            last_editing: doc.last_editing.to_string(),
+            id: doc.id,
+            title: doc.title,
+            uid: doc.uid,
+            content: doc.content.unwrap_or_default(),
             sheets: vec![],
             change_id: 0,
+            crdt_state: vec![],
         }
     }
 }
-impl Into<SheetEntity> for sheet::SheetModel {
-    fn into(self) -> SheetEntity {
+impl From<sheet::SheetModel> for SheetEntity {
+    fn from(sheet: sheet::SheetModel) -> Self {
         SheetEntity {
-            id: self.id,
-            title: self.title,
-            color: self.color.unwrap_or_default(),
-            created_date: self.created_date.to_string(),
-            last_editing: self.last_editing.to_string(),
-            uid: self.uid,
-            created_by_id: self.created_by_id.unwrap_or_default(),
-            project_id: self.project_id,
+            id: sheet.id,
+            title: sheet.title,
+            color: sheet.color.unwrap_or_default(),
+            created_date: sheet.created_date.to_string(),
+            last_editing: sheet.last_editing.to_string(),
+            uid: sheet.uid,
+            created_by_id: sheet.created_by_id.unwrap_or_default(),
+            project_id: sheet.project_id,
+        }
+    }
+}
+
+impl From<sheet::SheetModel> for OpenSheetResponse {
+    fn from(sheet: sheet::SheetModel) -> Self {
+        OpenSheetResponse {
+            id: sheet.id,
+            uid: sheet.uid,
+            color: sheet.color.unwrap_or_default(),
+            created_date: sheet.created_date.to_string(),
+            last_editing: sheet.last_editing.to_string(),
+            content: sheet.content.unwrap_or_default(),
+            title: sheet.title,
+            change_id: 0,
+            document_id: sheet.document_id,
+            project_id: sheet.project_id,
+        }
+    }
+}
+
+impl From<document_revision::DocumentRevisionModel> for RevisionEntity {
+    fn from(revision: document_revision::DocumentRevisionModel) -> Self {
+        RevisionEntity {
+            id: revision.id,
+            document_id: revision.document_id,
+            author_id: revision.author_id.unwrap_or_default(),
+            created_date: revision.created_date.to_string(),
+            content: revision.content.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<sea_orm_active_enums::ProjectRole> for ProjectRole {
+    fn from(role: sea_orm_active_enums::ProjectRole) -> Self {
+        match role {
+            sea_orm_active_enums::ProjectRole::Viewer => ProjectRole::Viewer,
+            sea_orm_active_enums::ProjectRole::Commenter => ProjectRole::Commenter,
+            sea_orm_active_enums::ProjectRole::Editor => ProjectRole::Editor,
+            sea_orm_active_enums::ProjectRole::Owner => ProjectRole::Owner,
         }
     }
 }
@@ -1,30 +1,66 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    docs::{doc_event::Event, *},
-    docs_cache::DocsCache,
-    queries,
+    docs::{self, doc_event::Event, *},
+    docs_cache::{DocKey, DocsCache},
+    project_access::ProjectAccess,
+    queries, summarizer,
+    token_revocation::REVOKED_TOKENS,
     utils::{get_snowflake, unpack_req},
+    UserId,
 };
 use dashmap::DashMap;
+use doscenario_models::sea_orm_active_enums::ProjectRole;
 use tokio::sync::mpsc::{self, Sender};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 pub type SenderChan = Sender<Result<DocEvent, Status>>;
 
+#[derive(Debug)]
+pub struct DocSession {
+    user_id: String,
+    tx: Arc<SenderChan>,
+}
+
 #[derive(Debug, Clone)]
 pub struct DocsService {
-    // Doc streams, map a doc id to a map of session id with a sender channel
-    doc_streams: Arc<DashMap<i32, HashMap<i64, Arc<SenderChan>>>>,
+    // Doc and sheet streams, map a doc key to a map of session id with the session user and sender channel
+    doc_streams: Arc<DashMap<DocKey, HashMap<i64, DocSession>>>,
     doc_cache: Arc<DocsCache>,
+    // Every call is checked against the members of the project of its document
+    project_access: Arc<ProjectAccess>,
+    // Users allowed to call the admin RPCs, from the comma separated `ADMIN_USERS`
+    admins: Arc<HashSet<String>>,
 }
 impl DocsService {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(doc_cache: Arc<DocsCache>, project_access: Arc<ProjectAccess>) -> Self {
+        let service = Self {
             doc_streams: Arc::new(DashMap::new()),
-            doc_cache: DocsCache::new_arc(),
-        }
+            doc_cache,
+            project_access,
+            admins: Arc::new(
+                std::env::var("ADMIN_USERS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|id| id.trim().to_string())
+                    .filter(|id| !id.is_empty())
+                    .collect(),
+            ),
+        };
+        let project_access = service.project_access.clone();
+        tokio::spawn(async move {
+            let delay = Duration::from_secs(60);
+            loop {
+                tokio::time::sleep(delay).await;
+                project_access.purge_expired();
+            }
+        });
+        service
     }
 }
 
@@ -32,77 +68,38 @@ impl DocsService {
 impl docs_server::Docs for DocsService {
     // Doc event stream
     type SubscribeDocStream = ReceiverStream<Result<DocEvent, Status>>;
+    // Sheet event stream
+    type SubscribeSheetStream = ReceiverStream<Result<DocEvent, Status>>;
 
     async fn subscribe_doc(
         &self,
         request: Request<DocIdentityRequest>,
     ) -> Result<Response<Self::SubscribeDocStream>, Status> {
-        let (tx, rx) = mpsc::channel(64);
         let (data, user_id) = unpack_req(request);
-        let user = queries::get_user(&user_id.0).await?;
-
-        let session_id = get_snowflake().await;
-        let doc_id = data.id;
-        // If a doc is already open send an open event to everyone
-        // Otherwise create a doc stream map
-        if let Some(mut subs) = self.doc_streams.get_mut(&data.id) {
-            let res = futures::future::join_all(subs.values().map(|tx| {
-                tx.send(Ok(DocEvent {
-                    event: Some(doc_event::Event::Open(DocEventOpen {
-                        user_id: user_id.0.clone(),
-                        user_name: user.name.clone(),
-                        id: data.id,
-                        session_id,
-                    })),
-                }))
-            }))
-            .await;
-            for r in res {
-                if let Err(e) = r {
-                    log::error!("Cannot send open event: {:?}", e);
-                }
-            }
-            subs.insert(session_id, Arc::new(tx));
-        } else {
-            let mut map = HashMap::new();
-            map.insert(session_id, Arc::new(tx));
-            self.doc_streams.insert(data.id, map);
-        }
-        log::info!("Doc stream created session_id: {session_id}, doc_id: {doc_id}");
-        let tx = self
-            .doc_streams
-            .get(&data.id)
-            .unwrap()
-            .get(&session_id)
-            .unwrap()
-            .clone();
-
-        tx.send(Ok(DocEvent {
-            event: Some(doc_event::Event::Subscribed(DocEventSubscribed {
-                id: data.id,
-                session_id,
-            })),
-        }))
-        .await
-        .map_err(|_| Status::internal("Cannot send message"))?;
-
-        self.attach_unsubscribe(tx.clone(), session_id, data.id, user_id.0.clone());
-
-        Ok(Response::new(ReceiverStream::new(rx)))
+        let key = DocKey::Doc(data.id);
+        let role = self
+            .project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let stream = self.subscribe(key, user_id, role).await?;
+        Ok(Response::new(stream))
     }
     /// Open a document, return the document info, sheets, content and change id
     /// A cache entry with the doc is created if it doesn't exist
-    /// If the doc entry already exists the content is built from the changes
+    /// If the doc entry already exists the content is taken from the cache
     async fn open_doc(
         &self,
         request: Request<OpenDocRequest>,
     ) -> Result<Response<OpenDocResponse>, Status> {
-        let data = request.into_inner();
+        let (data, user_id) = unpack_req(request);
         log::info!("Open doc request: {:?}", data);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Viewer)
+            .await?;
         let (doc, sheets, (content, change_id)) = tokio::try_join!(
             queries::get_document(&data.id),
             queries::get_doc_sheets(&data.id),
-            self.doc_cache.register_doc(data.id)
+            self.doc_cache.register_doc(DocKey::Doc(data.id))
         )
         .map_err(|e| {
             log::error!("Error opening doc: {:?}", e);
@@ -122,11 +119,14 @@ impl docs_server::Docs for DocsService {
         request: Request<CreateDocRequest>,
     ) -> Result<Response<OpenDocResponse>, Status> {
         let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check_project(&user_id.0, data.project_id, ProjectRole::Editor)
+            .await?;
         let doc_id = queries::create_document(&data.title, &data.project_id, &user_id.0).await?;
         let (doc, sheets, (content, change_id)) = tokio::try_join!(
             queries::get_document(&doc_id),
             queries::get_doc_sheets(&doc_id),
-            self.doc_cache.register_doc(doc_id)
+            self.doc_cache.register_doc(DocKey::Doc(doc_id))
         )
         .map_err(|e| {
             log::error!("Error opening doc: {:?}", e);
@@ -140,37 +140,72 @@ impl docs_server::Docs for DocsService {
         Ok(Response::new(res))
     }
 
+    /// Copy a document with the content of its open texts and open the copy
+    async fn duplicate_doc(
+        &self,
+        request: Request<DuplicateDocRequest>,
+    ) -> Result<Response<OpenDocResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Viewer)
+            .await?;
+        let (doc, sheets, tags, content) = tokio::try_join!(
+            queries::get_document(&data.id),
+            queries::get_doc_sheets(&data.id),
+            queries::get_document_tags(&data.id),
+            self.doc_cache.get_content(DocKey::Doc(data.id))
+        )?;
+        let sheet_contents = futures::future::try_join_all(
+            sheets
+                .iter()
+                .map(|sheet| self.doc_cache.get_content(DocKey::Sheet(sheet.id))),
+        )
+        .await?;
+        let project_id = match data.project_id {
+            0 => doc.project_id,
+            id => id,
+        };
+        self.project_access
+            .check_project(&user_id.0, project_id, ProjectRole::Editor)
+            .await?;
+        let title = match data.title.trim() {
+            "" => doc.title.clone(),
+            title => title.to_string(),
+        };
+        let sheets: Vec<_> = sheets.into_iter().zip(sheet_contents).collect();
+        let doc_id = queries::duplicate_document(
+            &doc,
+            &title,
+            &content,
+            &sheets,
+            &tags,
+            &project_id,
+            &user_id.0,
+        )
+        .await?;
+        log::info!("Duplicated doc {} to doc {}", data.id, doc_id);
+
+        let (doc, sheets, (content, change_id)) = tokio::try_join!(
+            queries::get_document(&doc_id),
+            queries::get_doc_sheets(&doc_id),
+            self.doc_cache.register_doc(DocKey::Doc(doc_id))
+        )?;
+        let mut res: OpenDocResponse = doc.into();
+        res.sheets = sheets.into_iter().map(|s| s.into()).collect();
+        res.content = content;
+        res.change_id = change_id;
+        Ok(Response::new(res))
+    }
+
     /// Grpc call to write to a document
+    /// The changes are transformed against concurrent writes before being broadcasted
     async fn write_doc(&self, request: Request<DocWriteRequest>) -> Result<Response<()>, Status> {
         let (data, user_id) = unpack_req(request);
-        let changes = data
-            .changes
-            .clone()
-            .into_iter()
-            .map(|c| c.change)
-            .filter(|c| c.is_some())
-            .map(|c| c.unwrap())
-            .collect();
-        self.doc_cache
-            .update_doc(data.session_id, data.id, changes, data.change_id);
-        if let Some(subs) = self.doc_streams.get(&data.id) {
-            let res = futures::future::join_all(subs.values().map(|tx| {
-                tx.send(Ok(DocEvent {
-                    event: Some(Event::Write(DocEventWrite {
-                        user_id: user_id.0.clone(),
-                        id: data.id,
-                        session_id: data.session_id,
-                        changes: data.changes.clone(),
-                    })),
-                }))
-            }))
-            .await;
-            for r in res {
-                if let Err(e) = r {
-                    log::error!("Error sending write message: {:?}", e);
-                }
-            }
-        }
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Editor)
+            .await?;
+        self.write(key, data, user_id).await?;
         Ok(Response::new(()))
     }
 
@@ -179,26 +214,12 @@ impl docs_server::Docs for DocsService {
         request: Request<DocIdentityRequest>,
     ) -> Result<Response<()>, Status> {
         let (data, user_id) = unpack_req(request);
-        if let Some(subs) = self.doc_streams.get_mut(&data.id) {
-            let res = futures::future::join_all(subs.values().map(|tx| {
-                tx.send(Ok(DocEvent {
-                    event: Some(Event::Close(DocEventClose {
-                        user_id: user_id.0.clone(),
-                        id: data.id,
-                        session_id: data.session_id,
-                    })),
-                }))
-            }))
-            .await;
-            for r in res {
-                if let Err(e) = r {
-                    log::error!("Error sending close message: {:?}", e);
-                }
-            }
-            if subs.is_empty() {
-                self.doc_streams.remove(&data.id);
-            }
-        }
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        self.check_session(key, data.session_id, &user_id.0)?;
+        self.close(key, data.session_id, user_id).await;
         Ok(Response::new(()))
     }
 
@@ -207,27 +228,26 @@ impl docs_server::Docs for DocsService {
         request: Request<DocIdentityRequest>,
     ) -> Result<Response<()>, Status> {
         let (data, user_id) = unpack_req(request);
-        if let Some(subs) = self.doc_streams.get(&data.id) {
-            let res = futures::future::join_all(subs.values().map(|tx| {
-                tx.send(Ok(DocEvent {
-                    event: Some(Event::Remove(DocEventRemove {
-                        user_id: user_id.0.clone(),
-                        id: data.id,
-                    })),
-                }))
-            }))
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Owner)
+            .await?;
+        if self.doc_streams.contains_key(&key) {
+            self.broadcast(
+                key,
+                Event::Remove(DocEventRemove {
+                    user_id: user_id.0.clone(),
+                    id: data.id,
+                }),
+            )
             .await;
-            for r in res {
-                if let Err(e) = r {
-                    log::error!("Error sending remove message: {:?}", e);
-                }
-            }
         } else {
             log::warn!("Doc not found in cache: {}", data.id)
         }
-        self.doc_cache.clear_doc_cache(data.id);
+        self.doc_cache.clear_doc_cache(key).await;
         queries::delete_doc(&data.id).await?;
-        self.doc_streams.remove(&data.id);
+        self.doc_streams.remove(&key);
+        self.project_access.forget(key);
         Ok(Response::new(()))
     }
 
@@ -235,49 +255,608 @@ impl docs_server::Docs for DocsService {
         &self,
         request: Request<CrcCheckRequest>,
     ) -> Result<Response<CrcCheckResponse>, Status> {
-        let data = request.into_inner();
-        let valid = self.doc_cache.crc_check(data.id, data.crc).await?;
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let valid = self.doc_cache.crc_check(key, data.crc)?;
         Ok(Response::new(CrcCheckResponse { valid }))
     }
+
+    /// Send the writes missed by a client since a revision, usually after a failed CRC check
+    async fn get_changes_since(
+        &self,
+        request: Request<ChangesSinceRequest>,
+    ) -> Result<Response<ChangesSinceResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let res = self.doc_cache.get_changes_since(key, data.change_id)?;
+        Ok(Response::new(res))
+    }
+
+    async fn summarize_doc(
+        &self,
+        request: Request<SummarizeDocRequest>,
+    ) -> Result<Response<SummarizeDocResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let content = self.doc_cache.get_content(key).await?;
+        let summary =
+            summarizer::summarize(&content, summarizer::max_sentences(data.max_sentences));
+        Ok(Response::new(SummarizeDocResponse { summary }))
+    }
+
+    /// Move the cursor of a session and send it to the other sessions
+    async fn update_cursor(
+        &self,
+        request: Request<DocCursorRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Doc(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        self.check_session(key, data.session_id, &user_id.0)?;
+        let cursor = self.doc_cache.update_cursor(
+            key,
+            DocEventCursor {
+                session_id: data.session_id,
+                offset: data.offset,
+                id: data.id,
+                user_id: user_id.0,
+                selection_start: data.selection_start,
+                selection_end: data.selection_end,
+            },
+            data.change_id,
+        )?;
+        self.broadcast_except(key, Some(data.session_id), Event::Cursor(cursor))
+            .await;
+        Ok(Response::new(()))
+    }
+
+    /// Drop cached memberships, it only makes the next calls check the database again
+    async fn invalidate_memberships(
+        &self,
+        request: Request<InvalidateMembershipsRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        // Admins can invalidate any project, owners their own ones
+        if !self.admins.contains(&user_id.0) {
+            self.project_access
+                .check_project(&user_id.0, data.project_id, ProjectRole::Owner)
+                .await?;
+        }
+        let user_id = Some(data.user_id.as_str()).filter(|id| !id.is_empty());
+        self.project_access.invalidate(data.project_id, user_id);
+        Ok(Response::new(()))
+    }
+
+    async fn revoke_tokens(
+        &self,
+        request: Request<RevokeTokensRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        if !self.admins.contains(&user_id.0) {
+            return Err(Status::permission_denied("This requires an admin user"));
+        }
+        if data.user_id.is_empty() && data.jti.is_empty() {
+            return Err(Status::invalid_argument("Missing user id or jti"));
+        }
+        let revoked = REVOKED_TOKENS.get().unwrap();
+        if !data.jti.is_empty() {
+            let expires_at = Some(data.expires_at).filter(|exp| *exp > 0);
+            revoked.revoke_token(&data.jti, expires_at).await?;
+            log::info!("Token {} revoked by {}", data.jti, user_id.0);
+        }
+        if !data.user_id.is_empty() {
+            revoked.revoke_user(&data.user_id).await?;
+            log::info!("Tokens of user {} revoked by {}", data.user_id, user_id.0);
+            self.close_user_sessions(&data.user_id).await;
+        }
+        Ok(Response::new(()))
+    }
+
+    /// Revert the last group of writes made by a session, the changes are broadcasted as a write
+    async fn undo(&self, request: Request<DocIdentityRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.revert(DocKey::Doc(data.id), data.session_id, user_id, false)
+            .await?;
+        Ok(Response::new(()))
+    }
+
+    /// Revert the last undo made by a session
+    async fn redo(&self, request: Request<DocIdentityRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.revert(DocKey::Doc(data.id), data.session_id, user_id, true)
+            .await?;
+        Ok(Response::new(()))
+    }
+
+    async fn list_revisions(
+        &self,
+        request: Request<DocIdentityRequest>,
+    ) -> Result<Response<ListRevisionsResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Viewer)
+            .await?;
+        let revisions = queries::get_revisions(&data.id).await?;
+        Ok(Response::new(ListRevisionsResponse {
+            revisions: revisions.into_iter().map(|r| r.into()).collect(),
+        }))
+    }
+
+    async fn get_revision(
+        &self,
+        request: Request<RevisionRequest>,
+    ) -> Result<Response<RevisionEntity>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let revision = queries::get_revision(&data.id).await?;
+        self.project_access
+            .check(
+                &user_id.0,
+                DocKey::Doc(revision.document_id),
+                ProjectRole::Viewer,
+            )
+            .await?;
+        Ok(Response::new(revision.into()))
+    }
+
+    /// Replace the content of a document with one of its revisions
+    /// If the document is open the content is replaced through a write or a CRDT update broadcasted to every session
+    async fn restore_revision(
+        &self,
+        request: Request<RestoreRevisionRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Editor)
+            .await?;
+        let revision = queries::get_revision(&data.revision_id).await?;
+        if revision.document_id != data.id {
+            return Err(Status::invalid_argument(format!(
+                "Revision {} does not belong to doc {}",
+                data.revision_id, data.id
+            )));
+        }
+        let content = revision.content.unwrap_or_default();
+        let key = DocKey::Doc(data.id);
+        match self.doc_cache.get_change_id(key) {
+            Some(change_id) => {
+                let write = DocWriteRequest {
+                    id: data.id,
+                    changes: vec![Change {
+                        change: Some(change::Change::Replace(Replace { content })),
+                    }],
+                    session_id: data.session_id,
+                    change_id,
+                };
+                self.write(key, write, user_id).await?;
+            }
+            // A CRDT document would overwrite a content saved directly on its next flush
+            None if self.doc_cache.is_crdt_doc(data.id) => {
+                self.check_session(key, data.session_id, &user_id.0)?;
+                match self
+                    .doc_cache
+                    .replace_crdt_doc(data.id, &user_id.0, &content)
+                {
+                    Some(update) => {
+                        self.broadcast(
+                            key,
+                            Event::CrdtUpdate(DocEventCrdtUpdate {
+                                user_id: user_id.0,
+                                id: data.id,
+                                session_id: data.session_id,
+                                update,
+                            }),
+                        )
+                        .await
+                    }
+                    None => queries::set_doc_content(&data.id, &content).await?,
+                }
+            }
+            None => queries::set_doc_content(&data.id, &content).await?,
+        }
+        Ok(Response::new(()))
+    }
+
+    /// Open a document with the CRDT engine, return the document info, sheets, content and CRDT state
+    async fn open_doc_crdt(
+        &self,
+        request: Request<OpenDocRequest>,
+    ) -> Result<Response<OpenDocResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        log::info!("Open CRDT doc request: {:?}", data);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Viewer)
+            .await?;
+        let (doc, sheets, (content, crdt_state)) = tokio::try_join!(
+            queries::get_document(&data.id),
+            queries::get_doc_sheets(&data.id),
+            self.doc_cache.register_crdt_doc(data.id)
+        )
+        .map_err(|e| {
+            log::error!("Error opening CRDT doc: {:?}", e);
+            e
+        })?;
+
+        let mut res: OpenDocResponse = doc.into();
+        res.sheets = sheets.into_iter().map(|s| s.into()).collect();
+        res.content = content;
+        res.crdt_state = crdt_state;
+        Ok(Response::new(res))
+    }
+
+    /// Merge a CRDT update into a document and broadcast it
+    async fn write_doc_crdt(
+        &self,
+        request: Request<DocCrdtUpdateRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Editor)
+            .await?;
+        self.check_session(DocKey::Doc(data.id), data.session_id, &user_id.0)?;
+        self.doc_cache
+            .update_crdt_doc(data.id, &user_id.0, &data.update)?;
+        self.broadcast(
+            DocKey::Doc(data.id),
+            Event::CrdtUpdate(DocEventCrdtUpdate {
+                user_id: user_id.0,
+                id: data.id,
+                session_id: data.session_id,
+                update: data.update,
+            }),
+        )
+        .await;
+        Ok(Response::new(()))
+    }
+
+    /// Get the CRDT updates missing from a client given its state vector
+    async fn sync_doc_crdt(
+        &self,
+        request: Request<DocCrdtSyncRequest>,
+    ) -> Result<Response<DocCrdtSyncResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        self.project_access
+            .check(&user_id.0, DocKey::Doc(data.id), ProjectRole::Viewer)
+            .await?;
+        let update = self.doc_cache.sync_crdt_doc(data.id, &data.state_vector)?;
+        Ok(Response::new(DocCrdtSyncResponse { update }))
+    }
+
+    /// Open a sheet, return the sheet info, content and change id
+    /// A cache entry with the sheet is created if it doesn't exist
+    async fn open_sheet(
+        &self,
+        request: Request<OpenDocRequest>,
+    ) -> Result<Response<OpenSheetResponse>, Status> {
+        let (data, user_id) = unpack_req(request);
+        log::info!("Open sheet request: {:?}", data);
+        self.project_access
+            .check(&user_id.0, DocKey::Sheet(data.id), ProjectRole::Viewer)
+            .await?;
+        let (sheet, (content, change_id)) = tokio::try_join!(
+            queries::get_sheet(&data.id),
+            self.doc_cache.register_doc(DocKey::Sheet(data.id))
+        )
+        .map_err(|e| {
+            log::error!("Error opening sheet: {:?}", e);
+            e
+        })?;
+
+        let mut res: OpenSheetResponse = sheet.into();
+        res.content = content;
+        res.change_id = change_id;
+        Ok(Response::new(res))
+    }
+
+    async fn close_sheet(
+        &self,
+        request: Request<DocIdentityRequest>,
+    ) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Sheet(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        self.check_session(key, data.session_id, &user_id.0)?;
+        self.close(key, data.session_id, user_id).await;
+        Ok(Response::new(()))
+    }
+
+    async fn subscribe_sheet(
+        &self,
+        request: Request<DocIdentityRequest>,
+    ) -> Result<Response<Self::SubscribeSheetStream>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Sheet(data.id);
+        let role = self
+            .project_access
+            .check(&user_id.0, key, ProjectRole::Viewer)
+            .await?;
+        let stream = self.subscribe(key, user_id, role).await?;
+        Ok(Response::new(stream))
+    }
+
+    /// Grpc call to write to a sheet
+    /// The changes are transformed against concurrent writes before being broadcasted
+    async fn write_sheet(&self, request: Request<DocWriteRequest>) -> Result<Response<()>, Status> {
+        let (data, user_id) = unpack_req(request);
+        let key = DocKey::Sheet(data.id);
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Editor)
+            .await?;
+        self.write(key, data, user_id).await?;
+        Ok(Response::new(()))
+    }
 }
 
 impl DocsService {
+    /// Create a new session on a doc or a sheet and return its event stream
+    /// Other sessions are notified with an open event with the role of the user
+    async fn subscribe(
+        &self,
+        key: DocKey,
+        user_id: UserId,
+        role: ProjectRole,
+    ) -> Result<ReceiverStream<Result<DocEvent, Status>>, Status> {
+        let (tx, rx) = mpsc::channel(64);
+        let user = queries::get_user(&user_id.0).await?;
+
+        let session_id = get_snowflake().await;
+        self.broadcast(
+            key,
+            Event::Open(DocEventOpen {
+                user_id: user_id.0.clone(),
+                user_name: user.name,
+                id: key.id(),
+                session_id,
+                role: docs::ProjectRole::from(role) as i32,
+            }),
+        )
+        .await;
+        let tx = Arc::new(tx);
+        self.doc_streams.entry(key).or_default().insert(
+            session_id,
+            DocSession {
+                user_id: user_id.0.clone(),
+                tx: tx.clone(),
+            },
+        );
+        log::info!("Stream created session_id: {session_id}, {:?}", key);
+
+        tx.send(Ok(DocEvent {
+            event: Some(Event::Subscribed(DocEventSubscribed {
+                id: key.id(),
+                session_id,
+            })),
+        }))
+        .await
+        .map_err(|_| Status::internal("Cannot send message"))?;
+        for cursor in self.doc_cache.get_cursors(key) {
+            tx.send(Ok(DocEvent {
+                event: Some(Event::Cursor(cursor)),
+            }))
+            .await
+            .map_err(|_| Status::internal("Cannot send message"))?;
+        }
+
+        self.attach_unsubscribe(tx, session_id, key, user_id.0);
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Apply changes to a doc or a sheet and broadcast the transformed changes
+    async fn write(
+        &self,
+        key: DocKey,
+        data: DocWriteRequest,
+        user_id: UserId,
+    ) -> Result<(), Status> {
+        self.check_session(key, data.session_id, &user_id.0)?;
+        let changes = data.changes.into_iter().filter_map(|c| c.change).collect();
+        let (changes, change_id) = self
+            .doc_cache
+            .update_doc(data.session_id, &user_id.0, key, changes, data.change_id)
+            .await?;
+        self.broadcast_write(key, data.session_id, user_id, changes, change_id)
+            .await;
+        Ok(())
+    }
+
+    /// Undo or redo the last writes of a session and broadcast the reverting changes
+    async fn revert(
+        &self,
+        key: DocKey,
+        session_id: i64,
+        user_id: UserId,
+        redo: bool,
+    ) -> Result<(), Status> {
+        self.project_access
+            .check(&user_id.0, key, ProjectRole::Editor)
+            .await?;
+        self.check_session(key, session_id, &user_id.0)?;
+        let res = if redo {
+            self.doc_cache.redo(session_id, &user_id.0, key).await?
+        } else {
+            self.doc_cache.undo(session_id, &user_id.0, key).await?
+        };
+        let (changes, change_id) = res.ok_or_else(|| {
+            Status::failed_precondition(if redo {
+                "Nothing to redo"
+            } else {
+                "Nothing to undo"
+            })
+        })?;
+        self.broadcast_write(key, session_id, user_id, changes, change_id)
+            .await;
+        Ok(())
+    }
+
+    async fn broadcast_write(
+        &self,
+        key: DocKey,
+        session_id: i64,
+        user_id: UserId,
+        changes: Vec<change::Change>,
+        change_id: u64,
+    ) {
+        let changes = changes
+            .into_iter()
+            .map(|c| Change { change: Some(c) })
+            .collect();
+        self.broadcast(
+            key,
+            Event::Write(DocEventWrite {
+                user_id: user_id.0,
+                id: key.id(),
+                session_id,
+                changes,
+                change_id,
+            }),
+        )
+        .await;
+    }
+
+    /// Check that a session of a user is subscribed to a doc or a sheet
+    fn check_session(&self, key: DocKey, session_id: i64, user_id: &str) -> Result<(), Status> {
+        let subs = self.doc_streams.get(&key);
+        match subs.as_ref().and_then(|subs| subs.get(&session_id)) {
+            Some(session) if session.user_id == user_id => Ok(()),
+            Some(_) => Err(Status::permission_denied("Session belongs to another user")),
+            None => Err(Status::failed_precondition(format!(
+                "Session is not subscribed to {:?}",
+                key
+            ))),
+        }
+    }
+
+    /// Notify other sessions that a session closed a doc or a sheet
+    async fn close(&self, key: DocKey, session_id: i64, user_id: UserId) {
+        self.doc_cache.remove_session(key, session_id);
+        self.broadcast(
+            key,
+            Event::Close(DocEventClose {
+                user_id: user_id.0,
+                id: key.id(),
+                session_id,
+            }),
+        )
+        .await;
+        self.doc_streams.remove_if(&key, |_, subs| subs.is_empty());
+    }
+
+    /// Send an event to every session of a doc or a sheet
+    async fn broadcast(&self, key: DocKey, event: Event) {
+        self.broadcast_except(key, None, event).await
+    }
+
+    /// Send an event to every session of a doc or a sheet except `skip_session`
+    async fn broadcast_except(&self, key: DocKey, skip_session: Option<i64>, event: Event) {
+        let subs: Vec<Arc<SenderChan>> = match self.doc_streams.get(&key) {
+            Some(subs) => subs
+                .iter()
+                .filter(|(session_id, _)| Some(**session_id) != skip_session)
+                .map(|(_, session)| session.tx.clone())
+                .collect(),
+            None => return,
+        };
+        let res = futures::future::join_all(subs.iter().map(|tx| {
+            tx.send(Ok(DocEvent {
+                event: Some(event.clone()),
+            }))
+        }))
+        .await;
+        for r in res {
+            if let Err(e) = r {
+                log::error!("Error sending event to {:?}: {:?}", key, e);
+            }
+        }
+    }
+
     pub fn attach_unsubscribe(
         &self,
-        tx: Arc<Sender<Result<DocEvent, Status>>>,
+        tx: Arc<SenderChan>,
         session_id: i64,
-        doc_id: i32,
+        key: DocKey,
         user_id: String,
     ) {
-        let doc_streams = self.doc_streams.clone();
-        let doc_cache = self.doc_cache.clone();
+        let service = self.clone();
         tokio::spawn(async move {
             tx.closed().await;
-            log::info!("Doc stream closed session_id: {session_id}, doc_id: {doc_id}");
-            if let Some(mut subs) = doc_streams.get_mut(&doc_id) {
-                subs.remove(&session_id);
-                let res = futures::future::join_all(subs.values().map(|tx| {
-                    tx.send(Ok(DocEvent {
-                        event: Some(Event::Close(DocEventClose {
-                            user_id: user_id.clone(),
-                            id: doc_id,
-                            session_id,
-                        })),
-                    }))
-                }))
-                .await;
-                for r in res {
-                    if let Err(e) = r {
-                        log::error!("Error sending unsubscribe message: {:?}", e);
-                    }
-                }
-                if subs.is_empty() {
-                    doc_streams.remove(&doc_id);
-                    if let Err(e) = doc_cache.remove_doc(doc_id).await {
-                        log::error!("Error removing doc from cache: {:?}", e);
-                    };
-                }
-            }
+            log::info!("Stream closed session_id: {session_id}, {:?}", key);
+            service.end_session(key, session_id, user_id).await;
         });
     }
+
+    /// Remove a session, notify the other sessions and drop the doc from the cache if it was the last one
+    /// Return the sender of the session if it wasn't already removed
+    async fn end_session(
+        &self,
+        key: DocKey,
+        session_id: i64,
+        user_id: String,
+    ) -> Option<Arc<SenderChan>> {
+        self.doc_cache.remove_session(key, session_id);
+        let (session, is_empty) = match self.doc_streams.get_mut(&key) {
+            Some(mut subs) => (subs.remove(&session_id)?, subs.is_empty()),
+            None => return None,
+        };
+        self.broadcast(
+            key,
+            Event::Close(DocEventClose {
+                user_id,
+                id: key.id(),
+                session_id,
+            }),
+        )
+        .await;
+        if is_empty
+            && self
+                .doc_streams
+                .remove_if(&key, |_, subs| subs.is_empty())
+                .is_some()
+        {
+            if let Err(e) = self.doc_cache.remove_doc(key).await {
+                log::error!("Error removing doc from cache: {:?}", e);
+            };
+        }
+        Some(session.tx)
+    }
+
+    /// End every open session of a user whose tokens were revoked
+    /// The streams are ended with an unauthenticated status once the other sessions are notified
+    async fn close_user_sessions(&self, user_id: &str) {
+        let sessions: Vec<(DocKey, i64)> = self
+            .doc_streams
+            .iter()
+            .flat_map(|entry| {
+                let key = *entry.key();
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(_, session)| session.user_id == user_id)
+                    .map(|(session_id, _)| (key, *session_id))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        for (key, session_id) in sessions {
+            if let Some(tx) = self.end_session(key, session_id, user_id.to_string()).await {
+                log::info!("Stream revoked session_id: {session_id}, {:?}", key);
+                // The client may not read its stream anymore, so the status is sent without blocking the call
+                tokio::spawn(async move {
+                    tx.send(Err(Status::unauthenticated("Auth token has been revoked")))
+                        .await
+                        .ok();
+                });
+            }
+        }
+    }
 }
@@ -0,0 +1,446 @@
+//! Keys verifying the JWT of every call.
+//! Keys come from the `PRIVATE_KEY` HS256 secret, the RS256 or EdDSA PEM files of `JWT_PUBLIC_KEYS`
+//! and the local JWKS file of `JWT_JWKS`. Several keys can be active at once to rotate them
+//! and they are reloaded when the process receives SIGHUP.
+use std::{fs, sync::RwLock};
+
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::Status;
+
+pub static JWT_KEYS: OnceCell<JwtKeys> = OnceCell::new();
+
+pub fn load_jwt_keys() {
+    let keys = JwtKeys::from_env().expect("Failed to load JWT keys");
+    JWT_KEYS.set(keys).ok().expect("Failed to set JWT keys");
+}
+
+/// Reload the keys every time the process receives SIGHUP
+pub async fn reload_on_hangup() {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen to SIGHUP");
+    while hangup.recv().await.is_some() {
+        if let Some(keys) = JWT_KEYS.get() {
+            keys.reload();
+        }
+    }
+}
+
+struct VerifyingKey {
+    // Tokens with a `kid` header are only checked with the key of this id
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Configured locations of the keys, loaded again on reload
+#[derive(Debug, Default)]
+struct KeySources {
+    secret: Option<String>,
+    // PEM files, each one optionally prefixed by its key id: `kid=path`
+    public_keys: Vec<String>,
+    jwks: Option<String>,
+}
+
+pub struct JwtKeys {
+    sources: KeySources,
+    keys: RwLock<Vec<VerifyingKey>>,
+    // Claims checks shared by every key, `exp` and `nbf` are always checked
+    validation: Validation,
+}
+
+impl JwtKeys {
+    /// Load the keys and the claims checks from the environment
+    /// `JWT_ISSUER` and `JWT_AUDIENCE` are comma separated lists of accepted values
+    /// and `JWT_LEEWAY` is the clock skew allowed on `exp` and `nbf` in seconds, 60 by default
+    pub fn from_env() -> Result<Self, String> {
+        let mut validation = Validation::default();
+        validation.validate_nbf = true;
+        if let Some(issuers) = env_list("JWT_ISSUER") {
+            validation.set_issuer(&issuers);
+        }
+        if let Some(audiences) = env_list("JWT_AUDIENCE") {
+            validation.set_audience(&audiences);
+        }
+        if let Ok(leeway) = std::env::var("JWT_LEEWAY") {
+            validation.leeway = leeway
+                .parse()
+                .map_err(|_| format!("Invalid JWT_LEEWAY: {}", leeway))?;
+        }
+        let sources = KeySources {
+            secret: std::env::var("PRIVATE_KEY").ok(),
+            public_keys: env_list("JWT_PUBLIC_KEYS").unwrap_or_default(),
+            jwks: std::env::var("JWT_JWKS").ok(),
+        };
+        Self::new(sources, validation)
+    }
+
+    fn new(sources: KeySources, validation: Validation) -> Result<Self, String> {
+        Ok(Self {
+            keys: RwLock::new(sources.load()?),
+            sources,
+            validation,
+        })
+    }
+
+    /// Load the keys again, the current keys are kept if they can't be loaded
+    pub fn reload(&self) {
+        match self.sources.load() {
+            Ok(keys) => {
+                log::info!("Reloaded {} JWT keys", keys.len());
+                *self.keys.write().unwrap() = keys;
+            }
+            Err(e) => log::error!(
+                "Error while reloading JWT keys, keeping current ones: {}",
+                e
+            ),
+        }
+    }
+
+    /// Clock skew allowed on `exp` and `nbf` in seconds
+    pub fn leeway(&self) -> u64 {
+        self.validation.leeway
+    }
+
+    /// Verify a token and decode its claims
+    /// The key is selected by the `kid` header if the token has one, an unknown id is rejected.
+    /// Otherwise every key of its algorithm is tried
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, Status> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| Status::unauthenticated(format!("Invalid auth token: {}", e)))?;
+        let keys = self.keys.read().unwrap();
+        let candidates = keys.iter().filter(|key| {
+            key.algorithm == header.alg && (header.kid.is_none() || key.kid == header.kid)
+        });
+        let mut error = match &header.kid {
+            Some(kid) => format!("no key {:?} for algorithm {:?}", kid, header.alg),
+            None => format!("no key for algorithm {:?}", header.alg),
+        };
+        for key in candidates {
+            let mut validation = self.validation.clone();
+            validation.algorithms = vec![key.algorithm];
+            match jsonwebtoken::decode::<T>(token, &key.key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => error = e.to_string(),
+            }
+        }
+        Err(Status::unauthenticated(format!(
+            "Invalid auth token: {}",
+            error
+        )))
+    }
+}
+
+impl KeySources {
+    /// Load every configured key, at least one must be configured
+    fn load(&self) -> Result<Vec<VerifyingKey>, String> {
+        let mut keys = Vec::new();
+        if let Some(secret) = &self.secret {
+            keys.push(VerifyingKey {
+                kid: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+        for entry in self.public_keys.iter() {
+            let (kid, path) = match entry.split_once('=') {
+                Some((kid, path)) => (Some(kid.to_string()), path),
+                None => (None, entry.as_str()),
+            };
+            let pem = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let (algorithm, key) = if let Ok(key) = DecodingKey::from_rsa_pem(&pem) {
+                (Algorithm::RS256, key)
+            } else if let Ok(key) = DecodingKey::from_ed_pem(&pem) {
+                (Algorithm::EdDSA, key)
+            } else {
+                return Err(format!("{} is not an RSA or Ed25519 public key", path));
+            };
+            keys.push(VerifyingKey {
+                kid,
+                algorithm,
+                key,
+            });
+        }
+        if let Some(path) = &self.jwks {
+            let content = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let set: JwkSet = serde_json::from_slice(&content)
+                .map_err(|e| format!("Invalid JWKS file {}: {}", path, e))?;
+            for jwk in set.keys.iter() {
+                let (algorithm, key) = jwk_key(jwk).map_err(|e| {
+                    format!("Invalid key {:?} in {}: {}", jwk.common.key_id, path, e)
+                })?;
+                keys.push(VerifyingKey {
+                    kid: jwk.common.key_id.clone(),
+                    algorithm,
+                    key,
+                });
+            }
+        }
+        if keys.is_empty() {
+            return Err(
+                "No JWT key configured, set PRIVATE_KEY, JWT_PUBLIC_KEYS or JWT_JWKS".into(),
+            );
+        }
+        Ok(keys)
+    }
+}
+
+/// Decoding key of a JWK and the only algorithm it verifies
+/// The `alg` parameter must belong to the key type, HMAC secrets are base64url encoded
+fn jwk_key(jwk: &Jwk) -> Result<(Algorithm, DecodingKey), String> {
+    let algorithm = jwk_algorithm(jwk);
+    let family_matches = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(
+            algorithm,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(_) => {
+            matches!(algorithm, Algorithm::ES256 | Algorithm::ES384)
+        }
+        AlgorithmParameters::OctetKeyPair(_) => algorithm == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => matches!(
+            algorithm,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ),
+    };
+    if !family_matches {
+        return Err(format!(
+            "algorithm {:?} does not match the key type",
+            algorithm
+        ));
+    }
+    let key = match &jwk.algorithm {
+        AlgorithmParameters::OctetKey(params) => {
+            let secret = base64::decode_config(&params.value, base64::URL_SAFE_NO_PAD)
+                .map_err(|e| e.to_string())?;
+            DecodingKey::from_secret(&secret)
+        }
+        _ => DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?,
+    };
+    Ok((algorithm, key))
+}
+
+/// Algorithm of a JWK, taken from its `alg` parameter or guessed from its key type
+fn jwk_algorithm(jwk: &Jwk) -> Algorithm {
+    if let Some(algorithm) = jwk.common.algorithm {
+        return algorithm;
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Algorithm::RS256,
+        AlgorithmParameters::OctetKeyPair(_) => Algorithm::EdDSA,
+        AlgorithmParameters::EllipticCurve(params) if params.curve == EllipticCurve::P384 => {
+            Algorithm::ES384
+        }
+        AlgorithmParameters::EllipticCurve(_) => Algorithm::ES256,
+        AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+    }
+}
+
+/// Comma separated values of an environment variable
+pub fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::now_secs;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    fn write_jwks(name: &str, keys: Value) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "doscenario-jwks-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, json!({ "keys": keys }).to_string()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Load the keys of a JWKS file written for a single load
+    fn load_once(sources: KeySources) -> Result<JwtKeys, String> {
+        let path = sources.jwks.clone();
+        let keys = JwtKeys::new(sources, validation());
+        if let Some(path) = path {
+            fs::remove_file(path).unwrap();
+        }
+        keys
+    }
+
+    fn oct(kid: &str, alg: &str, k: &str) -> Value {
+        json!({ "kty": "oct", "kid": kid, "alg": alg, "k": k })
+    }
+
+    fn validation() -> Validation {
+        let mut validation = Validation::default();
+        validation.validate_nbf = true;
+        validation.leeway = 0;
+        validation
+    }
+
+    fn token(secret: &[u8], algorithm: Algorithm, kid: Option<&str>, claims: Value) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = kid.map(str::to_string);
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "user", "exp": now_secs() + 60 })
+    }
+
+    fn accepts(keys: &JwtKeys, token: &str) -> bool {
+        keys.decode::<Value>(token).is_ok()
+    }
+
+    #[test]
+    fn tokens_with_a_kid_are_only_checked_with_its_key() {
+        let sources = KeySources {
+            secret: Some("secret-a".to_string()),
+            jwks: Some(write_jwks(
+                "kid",
+                json!([
+                    oct("a", "HS256", "c2VjcmV0LWE"),
+                    oct("b", "HS256", "c2VjcmV0LWI"),
+                ]),
+            )),
+            ..Default::default()
+        };
+        let keys = load_once(sources).unwrap();
+        let secret = b"secret-a";
+        assert!(accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, Some("a"), claims())
+        ));
+        assert!(!accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, Some("b"), claims())
+        ));
+        // The secret without kid is not used for unknown ids
+        assert!(!accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, Some("c"), claims())
+        ));
+        assert!(accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, None, claims())
+        ));
+        assert!(accepts(
+            &keys,
+            &token(b"secret-b", Algorithm::HS256, None, claims())
+        ));
+    }
+
+    #[test]
+    fn jwks_keys_only_verify_their_algorithm() {
+        let sources = KeySources {
+            jwks: Some(write_jwks(
+                "alg",
+                json!([
+                    oct("a", "HS512", "c2VjcmV0LWE"),
+                    oct("c", "HS256", "-_8gc2VjcmV0LWM"),
+                ]),
+            )),
+            ..Default::default()
+        };
+        let keys = load_once(sources).unwrap();
+        let secret = b"secret-a";
+        assert!(accepts(
+            &keys,
+            &token(secret, Algorithm::HS512, Some("a"), claims())
+        ));
+        assert!(!accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, Some("a"), claims())
+        ));
+        assert!(!accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, None, claims())
+        ));
+        // Secrets are base64url encoded
+        let secret = b"\xfb\xff secret-c";
+        assert!(accepts(
+            &keys,
+            &token(secret, Algorithm::HS256, Some("c"), claims())
+        ));
+
+        let sources = KeySources {
+            jwks: Some(write_jwks(
+                "rsa-hmac",
+                json!([{ "kty": "RSA", "kid": "r", "alg": "HS256", "n": "AQAB", "e": "AQAB" }]),
+            )),
+            ..Default::default()
+        };
+        assert!(load_once(sources).is_err());
+    }
+
+    #[test]
+    fn reload_replaces_the_keys_unless_they_are_invalid() {
+        let path = write_jwks("reload", json!([oct("a", "HS256", "c2VjcmV0LWE")]));
+        let sources = KeySources {
+            jwks: Some(path.clone()),
+            ..Default::default()
+        };
+        let keys = JwtKeys::new(sources, validation()).unwrap();
+        let token_a = token(b"secret-a", Algorithm::HS256, Some("a"), claims());
+        let token_b = token(b"secret-b", Algorithm::HS256, Some("b"), claims());
+        assert!(accepts(&keys, &token_a));
+        assert!(!accepts(&keys, &token_b));
+
+        write_jwks("reload", json!([oct("b", "HS256", "c2VjcmV0LWI")]));
+        keys.reload();
+        assert!(!accepts(&keys, &token_a));
+        assert!(accepts(&keys, &token_b));
+
+        fs::write(&path, "not a JWKS").unwrap();
+        keys.reload();
+        assert!(accepts(&keys, &token_b));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn claims_are_checked() {
+        let sources = KeySources {
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let mut validation = validation();
+        validation.set_issuer(&["issuer"]);
+        validation.set_audience(&["audience"]);
+        let keys = JwtKeys::new(sources, validation).unwrap();
+        let check =
+            |claims: Value| accepts(&keys, &token(b"secret", Algorithm::HS256, None, claims));
+        let exp = now_secs() + 60;
+        assert!(check(
+            json!({ "sub": "user", "exp": exp, "iss": "issuer", "aud": "audience" })
+        ));
+        assert!(!check(
+            json!({ "sub": "user", "exp": exp, "iss": "other", "aud": "audience" })
+        ));
+        assert!(!check(
+            json!({ "sub": "user", "exp": exp, "iss": "issuer", "aud": "other" })
+        ));
+        assert!(!check(
+            json!({ "sub": "user", "exp": now_secs() - 10, "iss": "issuer", "aud": "audience" })
+        ));
+        assert!(!check(
+            json!({ "sub": "user", "exp": exp, "nbf": exp, "iss": "issuer", "aud": "audience" })
+        ));
+    }
+}
@@ -1,41 +1,89 @@
 use crate::database::load_mysql_pool;
+use auth::auth_server::AuthServer;
+use auth_service::AuthService;
+use blueprints::blueprints_server::BlueprintsServer;
+use blueprints_service::BlueprintsService;
 use docs::docs_server::DocsServer;
+use docs_cache::DocsCache;
 use docs_service::DocsService;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
-use lazy_static::lazy_static;
+use jwt_keys::{load_jwt_keys, reload_on_hangup, JWT_KEYS};
 use log::info;
 use logging_timer::time;
+use project_access::ProjectAccess;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use token_revocation::{load_revoked_tokens, refresh_revoked_tokens, REVOKED_TOKENS};
 use tonic::{transport::Server, Request, Status};
 use doscenario_utils::tonic_logger::TonicLoggerLayer;
 
+// tonic::Status is the error type of every service call, returned by the sync functions of these modules
+#[allow(clippy::result_large_err)]
+pub mod auth_service;
+pub mod blueprint_export;
+#[allow(clippy::result_large_err)]
+pub mod blueprint_graph;
+#[allow(clippy::result_large_err)]
+pub mod blueprint_json;
+pub mod blueprint_layout;
+pub mod blueprints_mapper;
+#[allow(clippy::result_large_err)]
+pub mod blueprints_service;
 pub mod database;
+#[allow(clippy::result_large_err)]
 pub mod docs_cache;
+#[allow(clippy::result_large_err)]
+pub mod docs_crdt;
 pub mod docs_mapper;
+#[allow(clippy::result_large_err)]
 pub mod docs_service;
+#[allow(clippy::result_large_err)]
+pub mod jwt_keys;
+#[allow(clippy::result_large_err)]
+pub mod node_locks;
+#[allow(clippy::result_large_err)]
+pub mod ot;
+#[allow(clippy::result_large_err)]
+pub mod project_access;
+#[allow(clippy::result_large_err)]
 pub mod queries;
+pub mod summarizer;
+#[allow(clippy::result_large_err)]
+pub mod text;
+pub mod token_revocation;
 pub mod utils;
+#[allow(clippy::result_large_err)]
+pub mod wal;
 
 pub mod docs {
+    // The proto comments are indented with tabs
+    #![allow(clippy::tabs_in_doc_comments)]
     tonic::include_proto!("docs");
 }
+pub mod blueprints {
+    tonic::include_proto!("blueprints");
+}
+pub mod auth {
+    tonic::include_proto!("auth");
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
+    // Used to revoke a single token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    // Used to revoke every token of a user issued before a time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    // Refresh tokens issued by the auth service are only accepted by its Refresh and Logout calls
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    refresh: bool,
 }
 #[derive(Debug, Clone)]
 pub struct UserId(String);
 
 #[time("debug")]
 fn check_auth(mut req: Request<()>) -> Result<Request<()>, Status> {
-    lazy_static! {
-        static ref PRIVATE_KEY: DecodingKey = DecodingKey::from_secret(
-            std::env::var("PRIVATE_KEY")
-                .expect("PRIVATE_KEY not set")
-                .as_bytes()
-        );
-    }
     let token = String::from_utf8(
         req.metadata()
             .get("authorization")
@@ -44,21 +92,19 @@ fn check_auth(mut req: Request<()>) -> Result<Request<()>, Status> {
             .to_vec(),
     )
     .map_err(|_| Status::unauthenticated("Invalid auth token"))?;
-    let res =
-        jsonwebtoken::decode::<Claims>(&token, &PRIVATE_KEY, &Validation::new(Algorithm::HS256))
-            .map_or_else(
-                |e| {
-                    Err(Status::unauthenticated(format!(
-                        "Invalid auth token: {}",
-                        e
-                    )))
-                },
-                |c| {
-                    req.extensions_mut().insert(UserId(c.claims.sub));
-                    Ok(req)
-                },
-            );
-    res
+    let claims: Claims = JWT_KEYS.get().unwrap().decode(&token)?;
+    if claims.refresh {
+        return Err(Status::unauthenticated("Refresh tokens are not auth tokens"));
+    }
+    if REVOKED_TOKENS
+        .get()
+        .unwrap()
+        .is_revoked(&claims.sub, claims.jti.as_deref(), claims.iat)
+    {
+        return Err(Status::unauthenticated("Auth token has been revoked"));
+    }
+    req.extensions_mut().insert(UserId(claims.sub));
+    Ok(req)
 }
 
 
@@ -71,14 +117,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::builder().init();
 
     let addr = "0.0.0.0:9090".parse().unwrap();
-    let docs_service = DocsServer::with_interceptor(DocsService::new(), check_auth);
+    load_jwt_keys();
+    tokio::spawn(reload_on_hangup());
+    let doc_cache = DocsCache::new_arc();
+    // Memberships are shared so that invalidating them applies to both services
+    let project_access = Arc::new(ProjectAccess::from_env());
 
     load_mysql_pool().await;
+    load_revoked_tokens().await;
+    tokio::spawn(refresh_revoked_tokens());
+    doc_cache.replay_wal().await;
+    let docs_service = DocsServer::with_interceptor(
+        DocsService::new(doc_cache.clone(), project_access.clone()),
+        check_auth,
+    );
+    let blueprints_service = BlueprintsServer::with_interceptor(
+        BlueprintsService::new(doc_cache, project_access),
+        check_auth,
+    );
+    // Login calls don't have a token yet
+    let auth_service =
+        AuthServer::new(AuthService::from_env().expect("Failed to load auth service"));
 
     info!("Listening on {:#?}", addr);
     Server::builder()
 		.layer(TonicLoggerLayer)
         .add_service(docs_service)
+        .add_service(blueprints_service)
+        .add_service(auth_service)
         .serve(addr)
         .await
         .unwrap();
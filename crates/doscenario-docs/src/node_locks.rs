@@ -0,0 +1,155 @@
+//! Leases on blueprint nodes, a node locked by a session can only be edited by this session.
+//! A lease must be renewed before it expires and is released when its session closes.
+//! Locks only live in memory and are lost on restart, the `locked` column of the node table is left at 0.
+use std::time::{Duration, Instant};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tonic::Status;
+
+#[derive(Debug, Clone)]
+pub struct NodeLock {
+    pub blueprint_id: i32,
+    pub session_id: i64,
+    pub user_id: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct NodeLocks {
+    // Map a node id to its current lock
+    locks: DashMap<i32, NodeLock>,
+    lease: Duration,
+}
+
+impl NodeLocks {
+    /// Create the lock store, leases last `LOCK_LEASE` seconds, 30 by default
+    pub fn from_env() -> Self {
+        let lease = std::env::var("LOCK_LEASE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+        Self {
+            locks: DashMap::new(),
+            lease: Duration::from_secs(lease),
+        }
+    }
+
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+
+    /// Lock a node for a session or renew its lease if the session already holds it
+    /// Return true if the lock was acquired and false if it was renewed,
+    /// with the expired lock of another session it replaced
+    pub fn acquire(
+        &self,
+        blueprint_id: i32,
+        node_id: i32,
+        session_id: i64,
+        user_id: &str,
+    ) -> Result<(bool, Option<NodeLock>), Status> {
+        let lock = NodeLock {
+            blueprint_id,
+            session_id,
+            user_id: user_id.to_string(),
+            expires_at: Instant::now() + self.lease,
+        };
+        match self.locks.entry(node_id) {
+            Entry::Occupied(mut entry) => {
+                let current = entry.get();
+                if current.session_id != session_id && current.expires_at > Instant::now() {
+                    return Err(Status::failed_precondition(
+                        "Node is locked by another session",
+                    ));
+                }
+                let renewed = current.session_id == session_id;
+                let previous = entry.insert(lock);
+                Ok((!renewed, (!renewed).then_some(previous)))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(lock);
+                Ok((true, None))
+            }
+        }
+    }
+
+    /// Release the lock of a node held by a session, return false if the session doesn't hold it
+    pub fn release(&self, node_id: i32, session_id: i64) -> bool {
+        self.locks
+            .remove_if(&node_id, |_, lock| lock.session_id == session_id)
+            .is_some()
+    }
+
+    /// Release every lock held by a session, return the ids of the unlocked nodes
+    pub fn release_session(&self, session_id: i64) -> Vec<(i32, NodeLock)> {
+        self.take(|lock| lock.session_id == session_id)
+    }
+
+    /// Release every lock whose lease expired, return the ids of the unlocked nodes
+    pub fn release_expired(&self) -> Vec<(i32, NodeLock)> {
+        let now = Instant::now();
+        self.take(|lock| lock.expires_at <= now)
+    }
+
+    /// Check that a node is not locked by another session than `session_id`
+    pub fn check(&self, node_id: i32, session_id: i64) -> Result<(), Status> {
+        match self.locks.get(&node_id) {
+            Some(lock) if lock.session_id != session_id && lock.expires_at > Instant::now() => Err(
+                Status::failed_precondition("Node is locked by another session"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the lock held on a node
+    pub fn get(&self, node_id: i32) -> Option<NodeLock> {
+        self.locks
+            .get(&node_id)
+            .filter(|lock| lock.expires_at > Instant::now())
+            .map(|lock| lock.clone())
+    }
+
+    fn take(&self, f: impl Fn(&NodeLock) -> bool) -> Vec<(i32, NodeLock)> {
+        let node_ids: Vec<i32> = self
+            .locks
+            .iter()
+            .filter(|entry| f(entry.value()))
+            .map(|entry| *entry.key())
+            .collect();
+        node_ids
+            .into_iter()
+            .filter_map(|node_id| self.locks.remove_if(&node_id, |_, lock| f(lock)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locks(lease: Duration) -> NodeLocks {
+        NodeLocks {
+            locks: DashMap::new(),
+            lease,
+        }
+    }
+
+    #[test]
+    fn acquire_returns_the_expired_lock_it_replaces() {
+        let locks = locks(Duration::ZERO);
+        assert!(matches!(locks.acquire(1, 10, 100, "a"), Ok((true, None))));
+        let (acquired, expired) = locks.acquire(1, 10, 200, "b").unwrap();
+        assert!(acquired);
+        assert_eq!(expired.map(|lock| lock.session_id), Some(100));
+    }
+
+    #[test]
+    fn acquire_renews_and_rejects_other_sessions() {
+        let locks = locks(Duration::from_secs(30));
+        assert!(matches!(locks.acquire(1, 10, 100, "a"), Ok((true, None))));
+        assert!(matches!(locks.acquire(1, 10, 100, "a"), Ok((false, None))));
+        assert!(locks.acquire(1, 10, 200, "b").is_err());
+        assert!(locks.check(10, 200).is_err());
+        assert!(locks.check(10, 100).is_ok());
+    }
+}
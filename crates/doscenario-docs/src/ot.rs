@@ -0,0 +1,301 @@
+//! Operational transformation of concurrent document changes
+//!
+//! Two lists of changes made on the same revision are transformed against each other
+//! so that applying them in any order gives the same content.
+//! On ties the change that was applied first keeps its position.
+use crate::docs::{change::Change, DocEventCursor, Insert, Remove};
+use crate::text::utf16_len;
+use tonic::Status;
+
+/// Check that the positions and sizes of changes sent by a client can be transformed without overflow
+pub fn validate(changes: &[Change]) -> Result<(), Status> {
+    for change in changes {
+        let end = match change {
+            Change::Insert(ins) if ins.position >= 0 => {
+                ins.position.checked_add(utf16_len(&ins.content))
+            }
+            Change::Remove(rem) if rem.position >= 0 && rem.size >= 0 => {
+                rem.position.checked_add(rem.size)
+            }
+            Change::Replace(_) => Some(0),
+            _ => None,
+        };
+        if end.is_none() {
+            return Err(Status::invalid_argument(format!(
+                "Invalid change {:?}",
+                change
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the positions of a cursor sent by a client are not negative
+pub fn validate_cursor(cursor: &DocEventCursor) -> Result<(), Status> {
+    if cursor.offset < 0 || cursor.selection_start < 0 || cursor.selection_end < 0 {
+        return Err(Status::invalid_argument("Invalid cursor position"));
+    }
+    Ok(())
+}
+
+/// Transform `incoming` changes against `applied` changes that were made on the same revision.
+/// Return the incoming changes rebased on top of the applied ones.
+pub fn transform(incoming: Vec<Change>, applied: Vec<Change>) -> Vec<Change> {
+    transform_lists(incoming, applied).0
+}
+
+/// Shift the positions of a cursor by changes applied after it was placed
+pub fn transform_cursor(cursor: &mut DocEventCursor, changes: &[Change]) {
+    cursor.offset = transform_position(cursor.offset, changes);
+    cursor.selection_start = transform_position(cursor.selection_start, changes);
+    cursor.selection_end = transform_position(cursor.selection_end, changes);
+}
+
+/// Shift a position by changes applied after it was taken
+/// A position is pushed by an insert made right at it
+fn transform_position(position: i32, changes: &[Change]) -> i32 {
+    changes
+        .iter()
+        .fold(position, |position, change| match change {
+            Change::Insert(ins) if ins.position <= position => {
+                position.saturating_add(utf16_len(&ins.content))
+            }
+            Change::Insert(_) => position,
+            Change::Remove(rem) if position >= rem.position + rem.size => position - rem.size,
+            Change::Remove(rem) => position.min(rem.position),
+            Change::Replace(rep) => position.min(utf16_len(&rep.content)),
+        })
+}
+
+/// Transform two lists of changes made on the same revision.
+/// Return `(xs', ys')` so that `ys` then `xs'` gives the same content as `xs` then `ys'`.
+fn transform_lists(xs: Vec<Change>, ys: Vec<Change>) -> (Vec<Change>, Vec<Change>) {
+    let mut ys = ys;
+    let mut res = Vec::with_capacity(xs.len());
+    for x in xs {
+        let mut x_cur = vec![x];
+        let mut ys_next = Vec::with_capacity(ys.len());
+        for y in ys {
+            let (x_new, y_new) = if x_cur.len() == 1 {
+                transform_pair(x_cur.pop().unwrap(), y)
+            } else {
+                transform_lists(x_cur, vec![y])
+            };
+            x_cur = x_new;
+            ys_next.extend(y_new);
+        }
+        ys = ys_next;
+        res.extend(x_cur);
+    }
+    (res, ys)
+}
+
+/// Transform a single change `x` against a change `y` applied first
+fn transform_pair(x: Change, y: Change) -> (Vec<Change>, Vec<Change>) {
+    match (x, y) {
+        // The last replace always wins and discards everything made concurrently
+        (x @ Change::Replace(_), _) => (vec![x], vec![]),
+        (_, y @ Change::Replace(_)) => (vec![], vec![y]),
+        (Change::Insert(x), Change::Insert(y)) => {
+            if y.position <= x.position {
+                let position = x.position + utf16_len(&y.content);
+                (
+                    vec![Change::Insert(Insert { position, ..x })],
+                    vec![Change::Insert(y)],
+                )
+            } else {
+                let position = y.position + utf16_len(&x.content);
+                (
+                    vec![Change::Insert(x)],
+                    vec![Change::Insert(Insert { position, ..y })],
+                )
+            }
+        }
+        (Change::Insert(x), Change::Remove(y)) => transform_insert_remove(x, y),
+        (Change::Remove(x), Change::Insert(y)) => {
+            let (y, x) = transform_insert_remove(y, x);
+            (x, y)
+        }
+        (Change::Remove(x), Change::Remove(y)) => (
+            transform_remove_remove(&x, &y),
+            transform_remove_remove(&y, &x),
+        ),
+    }
+}
+
+/// Transform an insert and a concurrent remove against each other.
+/// If the insert lands inside the removed range it is kept and the remove is split around it.
+fn transform_insert_remove(ins: Insert, rem: Remove) -> (Vec<Change>, Vec<Change>) {
+    let len = utf16_len(&ins.content);
+    let end = rem.position + rem.size;
+    if ins.position <= rem.position {
+        let position = rem.position + len;
+        (
+            vec![Change::Insert(ins)],
+            vec![Change::Remove(Remove { position, ..rem })],
+        )
+    } else if ins.position >= end {
+        let position = ins.position - rem.size;
+        (
+            vec![Change::Insert(Insert { position, ..ins })],
+            vec![Change::Remove(rem)],
+        )
+    } else {
+        let before = Remove {
+            position: rem.position,
+            size: ins.position - rem.position,
+        };
+        let after = Remove {
+            position: rem.position + len,
+            size: end - ins.position,
+        };
+        (
+            vec![Change::Insert(Insert {
+                position: rem.position,
+                ..ins
+            })],
+            vec![Change::Remove(before), Change::Remove(after)],
+        )
+    }
+}
+
+/// Transform a remove against a concurrent remove, the overlapping part is only removed once
+fn transform_remove_remove(x: &Remove, y: &Remove) -> Vec<Change> {
+    let x_end = x.position + x.size;
+    let y_end = y.position + y.size;
+    if x_end <= y.position {
+        vec![Change::Remove(x.clone())]
+    } else if x.position >= y_end {
+        vec![Change::Remove(Remove {
+            position: x.position - y.size,
+            size: x.size,
+        })]
+    } else {
+        let overlap = x_end.min(y_end) - x.position.max(y.position);
+        let size = x.size - overlap;
+        if size > 0 {
+            vec![Change::Remove(Remove {
+                position: x.position.min(y.position),
+                size,
+            })]
+        } else {
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{docs::Replace, text};
+    use ropey::Rope;
+
+    fn ins(position: i32, content: &str) -> Change {
+        Change::Insert(Insert {
+            position,
+            content: content.to_string(),
+        })
+    }
+
+    fn rem(position: i32, size: i32) -> Change {
+        Change::Remove(Remove { position, size })
+    }
+
+    fn apply(content: &str, changes: &[Change]) -> String {
+        let mut rope = Rope::from_str(content);
+        for change in changes {
+            match change {
+                Change::Insert(i) => text::insert(&mut rope, i.position, &i.content).unwrap(),
+                Change::Remove(r) => text::remove(&mut rope, r.position, r.size).unwrap(),
+                Change::Replace(r) => rope = Rope::from_str(&r.content),
+            }
+        }
+        rope.to_string()
+    }
+
+    /// Every single insert, remove and replace that can be made on `content`
+    /// Positions are UTF-16 offsets that don't split a surrogate pair
+    fn all_changes(content: &str) -> Vec<Change> {
+        let mut positions = vec![0];
+        for c in content.chars() {
+            positions.push(positions.last().unwrap() + c.len_utf16() as i32);
+        }
+        let mut changes = vec![Change::Replace(Replace {
+            content: "new".to_string(),
+        })];
+        for (i, position) in positions.iter().enumerate() {
+            changes.push(ins(*position, "x😀"));
+            for end in positions[i + 1..].iter() {
+                changes.push(rem(*position, end - position));
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn transform_satisfies_tp1() {
+        let content = "hello";
+        let changes = all_changes(content);
+        for x in changes.iter() {
+            for y in changes.iter() {
+                let (x2, y2) = transform_lists(vec![x.clone()], vec![y.clone()]);
+                let xy = apply(&apply(content, std::slice::from_ref(y)), &x2);
+                let yx = apply(&apply(content, std::slice::from_ref(x)), &y2);
+                assert_eq!(xy, yx, "x: {:?}, y: {:?}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_satisfies_tp1_on_non_ascii_text() {
+        let content = "aé😀b";
+        let changes = all_changes(content);
+        for x in changes.iter() {
+            for y in changes.iter() {
+                let (x2, y2) = transform_lists(vec![x.clone()], vec![y.clone()]);
+                let xy = apply(&apply(content, std::slice::from_ref(y)), &x2);
+                let yx = apply(&apply(content, std::slice::from_ref(x)), &y2);
+                assert_eq!(xy, yx, "x: {:?}, y: {:?}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_lists_satisfies_tp1() {
+        let content = "abcd";
+        let xs = vec![ins(1, "X"), rem(0, 3)];
+        for y in all_changes(content) {
+            let (x2, y2) = transform_lists(xs.clone(), vec![y.clone()]);
+            let xy = apply(&apply(content, std::slice::from_ref(&y)), &x2);
+            let yx = apply(&apply(content, &xs), &y2);
+            assert_eq!(xy, yx, "y: {:?}", y);
+        }
+    }
+
+    #[test]
+    fn transform_keeps_intent_of_sequential_writes_of_a_session() {
+        // A session writes A then B on "hello" while another session removes "hello" (C)
+        let a = vec![ins(0, "abc")];
+        let c = vec![rem(0, 5)];
+        // The server applies C then A transformed against it
+        let a_server = transform(a.clone(), c.clone());
+        let server = apply(&apply("hello", &c), &a_server);
+        assert_eq!(server, "abc");
+        // B is buffered on the client until A is acknowledged, then rebased by the client on C
+        // and sent on the revision of A, so there is nothing left to transform it against
+        let b = vec![ins(8, "Z")];
+        let (_, c_client) = transform_lists(a, c);
+        let b_rebased = transform(b, c_client);
+        let server = apply(&server, &transform(b_rebased, vec![]));
+        assert_eq!(server, "abcZ");
+    }
+
+    #[test]
+    fn validate_rejects_changes_that_overflow() {
+        assert!(validate(&[ins(0, "abc"), rem(1, 2)]).is_ok());
+        assert!(validate(&[rem(5, i32::MAX)]).is_err());
+        assert!(validate(&[ins(i32::MAX, "a")]).is_err());
+        assert!(validate(&[rem(-1, 1)]).is_err());
+        assert!(validate(&[rem(1, -1)]).is_err());
+    }
+}
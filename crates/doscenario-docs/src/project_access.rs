@@ -0,0 +1,247 @@
+//! Membership and role checks of users in the projects of the texts and blueprints they edit.
+//! Memberships are cached for `MEMBERSHIP_TTL` seconds and can be invalidated when the members of a project change,
+//! the project of each text is cached until the text is removed and the one of each blueprint until restart.
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use doscenario_models::sea_orm_active_enums::ProjectRole;
+use tonic::Status;
+
+use crate::{docs_cache::DocKey, queries};
+
+#[derive(Debug)]
+pub struct ProjectAccess {
+    // Map a user and a project to the role of the user if they're a member and when it was checked
+    memberships: DashMap<(String, i32), (Option<ProjectRole>, Instant)>,
+    // Map a text to its project
+    projects: DashMap<DocKey, i32>,
+    // Map a blueprint to its project
+    blueprints: DashMap<i32, i32>,
+    ttl: Duration,
+}
+
+impl ProjectAccess {
+    /// Create the access cache, memberships are kept `MEMBERSHIP_TTL` seconds, 60 by default
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("MEMBERSHIP_TTL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+        Self::new(Duration::from_secs(ttl))
+    }
+
+    fn new(ttl: Duration) -> Self {
+        Self {
+            memberships: DashMap::new(),
+            projects: DashMap::new(),
+            blueprints: DashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Check that a user is a member of a project with at least the `min` role and return their role
+    pub async fn check_project(
+        &self,
+        user_id: &str,
+        project_id: i32,
+        min: ProjectRole,
+    ) -> Result<ProjectRole, Status> {
+        let key = (user_id.to_string(), project_id);
+        let role = match self.cached_role(&key) {
+            Some(role) => role,
+            None => {
+                let role = queries::get_project_role(&project_id, user_id).await?;
+                self.memberships.insert(key, (role, Instant::now()));
+                role
+            }
+        };
+        check_role(role, min)
+    }
+
+    /// Get the cached role of a user in a project unless it expired
+    /// The inner `None` is a cached non-membership
+    fn cached_role(&self, key: &(String, i32)) -> Option<Option<ProjectRole>> {
+        self.memberships
+            .get(key)
+            .filter(|entry| entry.1.elapsed() < self.ttl)
+            .map(|entry| entry.0)
+    }
+
+    /// Check that a user has at least the `min` role in the project of a text and return their role
+    pub async fn check(
+        &self,
+        user_id: &str,
+        key: DocKey,
+        min: ProjectRole,
+    ) -> Result<ProjectRole, Status> {
+        let project_id = self.get_project(key).await?;
+        self.check_project(user_id, project_id, min).await
+    }
+
+    /// Check that a user has at least the `min` role in the project of a blueprint and return their role
+    pub async fn check_blueprint(
+        &self,
+        user_id: &str,
+        blueprint_id: i32,
+        min: ProjectRole,
+    ) -> Result<ProjectRole, Status> {
+        let project_id = match self.blueprints.get(&blueprint_id) {
+            Some(project_id) => *project_id,
+            None => {
+                let project_id = queries::get_blueprint_project(&blueprint_id)
+                    .await?
+                    .ok_or_else(|| Status::not_found("Blueprint not found"))?;
+                self.blueprints.insert(blueprint_id, project_id);
+                project_id
+            }
+        };
+        self.check_project(user_id, project_id, min).await
+    }
+
+    /// Get the project of a text
+    pub async fn get_project(&self, key: DocKey) -> Result<i32, Status> {
+        if let Some(project_id) = self.projects.get(&key) {
+            return Ok(*project_id);
+        }
+        let project_id = match key {
+            DocKey::Doc(id) => queries::get_document_project(&id).await?,
+            DocKey::Sheet(id) => queries::get_sheet_project(&id).await?,
+            DocKey::NodeContent(id) | DocKey::NodeSummary(id) => {
+                queries::get_node_project(&id).await?
+            }
+        }
+        .ok_or_else(|| Status::not_found(format!("{:?} not found", key)))?;
+        self.projects.insert(key, project_id);
+        Ok(project_id)
+    }
+
+    /// Forget the project of a removed text
+    pub fn forget(&self, key: DocKey) {
+        self.projects.remove(&key);
+    }
+
+    /// Drop the cached memberships of a project, only the ones of a user if one is given
+    pub fn invalidate(&self, project_id: i32, user_id: Option<&str>) {
+        self.memberships.retain(|(user, project), _| {
+            *project != project_id || user_id.is_some_and(|id| id != user)
+        });
+    }
+
+    /// Drop the memberships checked more than `MEMBERSHIP_TTL` seconds ago
+    pub fn purge_expired(&self) {
+        self.memberships
+            .retain(|_, (_, checked_at)| checked_at.elapsed() < self.ttl);
+    }
+}
+
+/// Check that a membership has at least the `min` role
+fn check_role(role: Option<ProjectRole>, min: ProjectRole) -> Result<ProjectRole, Status> {
+    match role {
+        None => Err(Status::permission_denied(
+            "User is not a member of this project",
+        )),
+        Some(role) if role < min => Err(Status::permission_denied(format!(
+            "This requires the {} role, user is {}",
+            role_name(min),
+            role_name(role)
+        ))),
+        Some(role) => Ok(role),
+    }
+}
+
+fn role_name(role: ProjectRole) -> &'static str {
+    match role {
+        ProjectRole::Viewer => "viewer",
+        ProjectRole::Commenter => "commenter",
+        ProjectRole::Editor => "editor",
+        ProjectRole::Owner => "owner",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn roles_below_the_minimum_are_denied() {
+        let denied = |role, min| check_role(role, min).unwrap_err().code();
+        assert_eq!(denied(None, ProjectRole::Viewer), Code::PermissionDenied);
+        assert_eq!(
+            denied(Some(ProjectRole::Viewer), ProjectRole::Commenter),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            denied(Some(ProjectRole::Commenter), ProjectRole::Editor),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            denied(Some(ProjectRole::Editor), ProjectRole::Owner),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            check_role(Some(ProjectRole::Owner), ProjectRole::Editor).unwrap(),
+            ProjectRole::Owner
+        );
+        assert_eq!(
+            check_role(Some(ProjectRole::Viewer), ProjectRole::Viewer).unwrap(),
+            ProjectRole::Viewer
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_memberships_are_checked_without_query() {
+        let access = ProjectAccess::new(Duration::from_secs(60));
+        let now = Instant::now();
+        access
+            .memberships
+            .insert(("editor".to_string(), 1), (Some(ProjectRole::Editor), now));
+        access
+            .memberships
+            .insert(("outsider".to_string(), 1), (None, now));
+        let check = |user, min| access.check_project(user, 1, min);
+        assert_eq!(
+            check("editor", ProjectRole::Editor).await.unwrap(),
+            ProjectRole::Editor
+        );
+        assert!(check("editor", ProjectRole::Owner).await.is_err());
+        assert!(check("outsider", ProjectRole::Viewer).await.is_err());
+    }
+
+    #[test]
+    fn memberships_expire_after_the_ttl() {
+        let key = ("user".to_string(), 1);
+        let access = ProjectAccess::new(Duration::from_secs(60));
+        access
+            .memberships
+            .insert(key.clone(), (Some(ProjectRole::Viewer), Instant::now()));
+        assert_eq!(access.cached_role(&key), Some(Some(ProjectRole::Viewer)));
+        access.purge_expired();
+        assert_eq!(access.memberships.len(), 1);
+
+        let access = ProjectAccess::new(Duration::ZERO);
+        access
+            .memberships
+            .insert(key.clone(), (Some(ProjectRole::Viewer), Instant::now()));
+        assert_eq!(access.cached_role(&key), None);
+        access.purge_expired();
+        assert!(access.memberships.is_empty());
+    }
+
+    #[test]
+    fn invalidation_drops_the_memberships_of_a_project() {
+        let access = ProjectAccess::new(Duration::from_secs(60));
+        for key in [("a", 1), ("b", 1), ("a", 2)] {
+            access.memberships.insert(
+                (key.0.to_string(), key.1),
+                (Some(ProjectRole::Editor), Instant::now()),
+            );
+        }
+        access.invalidate(1, Some("a"));
+        assert!(!access.memberships.contains_key(&("a".to_string(), 1)));
+        assert!(access.memberships.contains_key(&("b".to_string(), 1)));
+        access.invalidate(1, None);
+        assert!(!access.memberships.contains_key(&("b".to_string(), 1)));
+        assert!(access.memberships.contains_key(&("a".to_string(), 2)));
+    }
+}
@@ -1,5 +1,19 @@
-use crate::database::POOL;
-use doscenario_models::{user::UserModel, document::DocumentModel, sheet::SheetModel};
+use std::collections::HashMap;
+
+use crate::{blueprint_json::BlueprintJson, database::POOL, docs_cache::DocKey};
+use doscenario_models::{
+    blueprint::BlueprintModel,
+    document::DocumentModel,
+    document_revision::DocumentRevisionModel,
+    node::NodeModel,
+    node_tag::NodeTagModel,
+    relationship::RelationshipModel,
+    sea_orm_active_enums::{ChildPole, ParentPole, ProjectRole, Type},
+    sheet::SheetModel,
+    tag::TagModel,
+    user::UserModel,
+};
+use sqlx::{MySql, Transaction};
 use uuid::Uuid;
 
 use tonic::Status;
@@ -12,6 +26,14 @@ pub async fn get_user(id: &String) -> Result<UserModel, Status> {
     Ok(user)
 }
 
+pub async fn get_user_by_name(name: &str) -> Result<Option<UserModel>, Status> {
+    sqlx::query_as("SELECT * FROM user WHERE name = ?")
+        .bind(name)
+        .fetch_optional(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))
+}
+
 pub async fn create_document(
     title: &String,
     project_id: &i32,
@@ -25,7 +47,7 @@ pub async fn create_document(
     .bind(user_id)
     .bind(user_id)
     .bind(project_id)
-	.bind(Uuid::new_v4())
+    .bind(Uuid::new_v4())
     .execute(POOL.get().unwrap())
     .await
     .map_err(|e| Status::data_loss(e.to_string()))?;
@@ -80,6 +102,44 @@ pub async fn get_doc_sheets(doc_id: &i32) -> Result<Vec<SheetModel>, Status> {
     Ok(sheets)
 }
 
+pub async fn get_sheet(id: &i32) -> Result<SheetModel, Status> {
+    let sheet = sqlx::query_as(
+        r#"SELECT id, createdDate,
+		documentId,
+		lastEditing,
+		projectId,
+		createdById,
+		lastEditorId,
+		title,
+		uid,
+		color FROM sheet WHERE id = ?"#,
+    )
+    .bind(id)
+    .fetch_one(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(sheet)
+}
+
+pub async fn get_sheet_content(id: &i32) -> Result<String, Status> {
+    let ContentResult { content } = sqlx::query_as("SELECT content FROM sheet WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(content.unwrap_or_default())
+}
+
+pub async fn set_sheet_content(id: &i32, content: &String) -> Result<(), Status> {
+    sqlx::query("UPDATE sheet SET content = ? WHERE id = ?")
+        .bind(content)
+        .bind(id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
 pub async fn set_doc_content(id: &i32, content: &String) -> Result<(), Status> {
     sqlx::query("UPDATE document SET content = ? WHERE id = ?")
         .bind(content)
@@ -90,11 +150,721 @@ pub async fn set_doc_content(id: &i32, content: &String) -> Result<(), Status> {
     Ok(())
 }
 
+/// Save the content of a cached text and the last write-ahead log record it includes in one transaction
+pub async fn save_text_content(
+    key: DocKey,
+    content: &String,
+    epoch: i64,
+    change_id: u64,
+) -> Result<(), Status> {
+    let update = match key {
+        DocKey::Doc(_) => "UPDATE document SET content = ? WHERE id = ?",
+        DocKey::Sheet(_) => "UPDATE sheet SET content = ? WHERE id = ?",
+        DocKey::NodeContent(_) => "UPDATE node SET content = ? WHERE id = ?",
+        DocKey::NodeSummary(_) => "UPDATE node SET summary = ? WHERE id = ?",
+    };
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    sqlx::query(update)
+        .bind(content)
+        .bind(key.id())
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    sqlx::query(
+        "INSERT INTO wal_checkpoint (textKey, epoch, changeId) VALUES (?, ?, ?)
+        ON DUPLICATE KEY UPDATE epoch = VALUES(epoch), changeId = VALUES(changeId)",
+    )
+    .bind(key.name())
+    .bind(epoch)
+    .bind(change_id)
+    .execute(&mut tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Epoch and change id of the last write-ahead log record included in the saved content of a text
+pub async fn get_wal_checkpoint(key: DocKey) -> Result<Option<(i64, u64)>, Status> {
+    sqlx::query_as("SELECT epoch, changeId FROM wal_checkpoint WHERE textKey = ?")
+        .bind(key.name())
+        .fetch_optional(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))
+}
+
 pub async fn delete_doc(id: &i32) -> Result<(), Status> {
-	sqlx::query("DELETE FROM document WHERE id = ?")
-		.bind(id)
-		.execute(POOL.get().unwrap())
-		.await
-		.map_err(|e| Status::data_loss(e.to_string()))?;
-	Ok(())
-}
\ No newline at end of file
+    sqlx::query("DELETE FROM document WHERE id = ?")
+        .bind(id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+pub async fn create_revision(
+    doc_id: &i32,
+    content: &String,
+    author_id: &Option<String>,
+) -> Result<(), Status> {
+    sqlx::query("INSERT INTO document_revision (documentId, content, authorId) VALUES (?, ?, ?)")
+        .bind(doc_id)
+        .bind(content)
+        .bind(author_id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Get the revisions of a document without their content, newest first
+pub async fn get_revisions(doc_id: &i32) -> Result<Vec<DocumentRevisionModel>, Status> {
+    let revisions = sqlx::query_as(
+        r#"SELECT id, documentId, authorId, createdDate
+		FROM document_revision WHERE documentId = ? ORDER BY createdDate DESC, id DESC"#,
+    )
+    .bind(doc_id)
+    .fetch_all(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(revisions)
+}
+
+pub async fn get_revision(id: &i32) -> Result<DocumentRevisionModel, Status> {
+    let revision = sqlx::query_as("SELECT * FROM document_revision WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(revision)
+}
+
+pub async fn get_blueprint(id: &i32) -> Result<BlueprintModel, Status> {
+    let blueprint = sqlx::query_as("SELECT * FROM blueprint WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(blueprint)
+}
+
+pub async fn get_blueprint_nodes(blueprint_id: &i32) -> Result<Vec<NodeModel>, Status> {
+    let nodes = sqlx::query_as("SELECT * FROM node WHERE blueprintId = ?")
+        .bind(blueprint_id)
+        .fetch_all(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(nodes)
+}
+
+pub async fn get_blueprint_relationships(
+    blueprint_id: &i32,
+) -> Result<Vec<RelationshipModel>, Status> {
+    let relationships = sqlx::query_as("SELECT * FROM relationship WHERE blueprintId = ?")
+        .bind(blueprint_id)
+        .fetch_all(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(relationships)
+}
+
+/// Get the links between the nodes of a blueprint and their tags
+pub async fn get_blueprint_node_tags(blueprint_id: &i32) -> Result<Vec<NodeTagModel>, Status> {
+    let node_tags = sqlx::query_as(
+        r#"SELECT node_tag.nodeId, node_tag.tagId FROM node_tag
+		JOIN node ON node.id = node_tag.nodeId WHERE node.blueprintId = ?"#,
+    )
+    .bind(blueprint_id)
+    .fetch_all(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(node_tags)
+}
+
+/// Get every tag used by the nodes of a blueprint
+pub async fn get_blueprint_tags(blueprint_id: &i32) -> Result<Vec<TagModel>, Status> {
+    let tags = sqlx::query_as(
+        r#"SELECT DISTINCT tag.* FROM tag
+		JOIN node_tag ON node_tag.tagId = tag.id
+		JOIN node ON node.id = node_tag.nodeId WHERE node.blueprintId = ?"#,
+    )
+    .bind(blueprint_id)
+    .fetch_all(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(tags)
+}
+
+pub async fn get_node(id: &i32) -> Result<NodeModel, Status> {
+    let node = sqlx::query_as("SELECT * FROM node WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(node)
+}
+
+pub async fn get_node_content(id: &i32) -> Result<String, Status> {
+    let ContentResult { content } = sqlx::query_as("SELECT content FROM node WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(content.unwrap_or_default())
+}
+
+pub async fn set_node_content(id: &i32, content: &String) -> Result<(), Status> {
+    sqlx::query("UPDATE node SET content = ? WHERE id = ?")
+        .bind(content)
+        .bind(id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+pub async fn get_node_summary(id: &i32) -> Result<String, Status> {
+    let ContentResult { content } =
+        sqlx::query_as("SELECT summary AS content FROM node WHERE id = ?")
+            .bind(id)
+            .fetch_one(POOL.get().unwrap())
+            .await
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(content.unwrap_or_default())
+}
+
+pub async fn set_node_summary(id: &i32, summary: &String) -> Result<(), Status> {
+    sqlx::query("UPDATE node SET summary = ? WHERE id = ?")
+        .bind(summary)
+        .bind(id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Create a node in a blueprint, the first node of a blueprint without root becomes its root
+pub async fn create_node(
+    blueprint_id: &i32,
+    x: i32,
+    y: i32,
+    color: &Option<String>,
+    user_id: &String,
+) -> Result<i32, Status> {
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    // The blueprint row is locked so that concurrent creations can't both create a root
+    sqlx::query("SELECT id FROM blueprint WHERE id = ? FOR UPDATE")
+        .bind(blueprint_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    let (roots,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM node WHERE blueprintId = ? AND isRoot = 1")
+            .bind(blueprint_id)
+            .fetch_one(&mut tx)
+            .await
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+    let node = sqlx::query(
+        r#"INSERT INTO node (blueprintId, x, y, color, createdById, lastEditorId, isRoot, locked)
+		VALUES (?, ?, ?, ?, ?, ?, ?, 0)"#,
+    )
+    .bind(blueprint_id)
+    .bind(x)
+    .bind(y)
+    .bind(color)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(roots == 0)
+    .execute(&mut tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(node.last_insert_id() as i32)
+}
+
+/// Move a node of a blueprint, return false if the node is not in this blueprint
+pub async fn move_node(
+    blueprint_id: &i32,
+    id: &i32,
+    x: i32,
+    y: i32,
+    user_id: &String,
+) -> Result<bool, Status> {
+    let res = sqlx::query(
+        "UPDATE node SET x = ?, y = ?, lastEditorId = ? WHERE id = ? AND blueprintId = ?",
+    )
+    .bind(x)
+    .bind(y)
+    .bind(user_id)
+    .bind(id)
+    .bind(blueprint_id)
+    .execute(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Move many nodes of a blueprint at once
+pub async fn move_nodes(
+    blueprint_id: &i32,
+    positions: &[(i32, i32, i32)],
+    user_id: &String,
+) -> Result<(), Status> {
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    for (id, x, y) in positions {
+        sqlx::query(
+            "UPDATE node SET x = ?, y = ?, lastEditorId = ? WHERE id = ? AND blueprintId = ?",
+        )
+        .bind(x)
+        .bind(y)
+        .bind(user_id)
+        .bind(id)
+        .bind(blueprint_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Delete a node of a blueprint with its relationships, return the ids of the deleted relationships
+pub async fn delete_node(blueprint_id: &i32, id: &i32) -> Result<Vec<i32>, Status> {
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    let relationships: Vec<(i32,)> = sqlx::query_as(
+        "SELECT id FROM relationship WHERE blueprintId = ? AND (parentId = ? OR childId = ?)",
+    )
+    .bind(blueprint_id)
+    .bind(id)
+    .bind(id)
+    .fetch_all(&mut tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    sqlx::query("DELETE FROM relationship WHERE blueprintId = ? AND (parentId = ? OR childId = ?)")
+        .bind(blueprint_id)
+        .bind(id)
+        .bind(id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    let res = sqlx::query("DELETE FROM node WHERE id = ? AND blueprintId = ?")
+        .bind(id)
+        .bind(blueprint_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    if res.rows_affected() == 0 {
+        return Err(Status::not_found("Node not found"));
+    }
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(relationships.into_iter().map(|(id,)| id).collect())
+}
+
+pub async fn get_relationship(id: &i32) -> Result<RelationshipModel, Status> {
+    let relationship = sqlx::query_as("SELECT * FROM relationship WHERE id = ?")
+        .bind(id)
+        .fetch_one(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(relationship)
+}
+
+pub async fn create_relationship(
+    blueprint_id: &i32,
+    parent_id: &i32,
+    child_id: &i32,
+    parent_pole: ParentPole,
+    child_pole: ChildPole,
+    r#type: Type,
+) -> Result<i32, Status> {
+    let relationship = sqlx::query(
+        r#"INSERT INTO relationship (blueprintId, parentId, childId, parentPole, childPole, type)
+		VALUES (?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(blueprint_id)
+    .bind(parent_id)
+    .bind(child_id)
+    .bind(parent_pole)
+    .bind(child_pole)
+    .bind(r#type)
+    .execute(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(relationship.last_insert_id() as i32)
+}
+
+/// Delete a relationship of a blueprint, return false if it is not in this blueprint
+pub async fn delete_relationship(blueprint_id: &i32, id: &i32) -> Result<bool, Status> {
+    let res = sqlx::query("DELETE FROM relationship WHERE id = ? AND blueprintId = ?")
+        .bind(id)
+        .bind(blueprint_id)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Create a blueprint in a project from its JSON format with new ids for its nodes and relationships
+/// Tags are found by title in the project and created if they don't exist
+pub async fn import_blueprint(
+    project_id: &i32,
+    user_id: &String,
+    blueprint: &BlueprintJson,
+) -> Result<i32, Status> {
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    let blueprint_id = sqlx::query(
+        r#"INSERT INTO blueprint (title, color, projectId, createdById, lastEditorId, uid)
+		VALUES (?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(&blueprint.title)
+    .bind(&blueprint.color)
+    .bind(project_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(Uuid::new_v4())
+    .execute(&mut tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?
+    .last_insert_id() as i32;
+
+    // Map the ids of the file to the created ids
+    let mut node_ids: HashMap<i32, i32> = HashMap::new();
+    let mut tag_ids: HashMap<String, i32> = HashMap::new();
+    for node in blueprint.nodes.iter() {
+        let node_id = sqlx::query(
+            r#"INSERT INTO node (blueprintId, isRoot, content, summary, x, y, color, createdById, lastEditorId, locked)
+			VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 0)"#,
+        )
+        .bind(blueprint_id)
+        .bind(node.is_root)
+        .bind(&node.content)
+        .bind(&node.summary)
+        .bind(node.x)
+        .bind(node.y)
+        .bind(&node.color)
+        .bind(user_id)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?
+        .last_insert_id() as i32;
+        node_ids.insert(node.id, node_id);
+
+        for tag in node.tags.iter() {
+            let tag_id = match tag_ids.get(&tag.title) {
+                Some(id) => *id,
+                None => {
+                    let id = find_or_create_tag(
+                        &mut tx,
+                        project_id,
+                        &tag.title,
+                        &tag.color,
+                        tag.primary,
+                        user_id,
+                    )
+                    .await?;
+                    tag_ids.insert(tag.title.clone(), id);
+                    id
+                }
+            };
+            sqlx::query("INSERT IGNORE INTO node_tag (nodeId, tagId) VALUES (?, ?)")
+                .bind(node_id)
+                .bind(tag_id)
+                .execute(&mut tx)
+                .await
+                .map_err(|e| Status::data_loss(e.to_string()))?;
+        }
+    }
+
+    for rel in blueprint.relationships.iter() {
+        let node_id = |id: &i32| {
+            node_ids.get(id).copied().ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Relationship {} uses unknown node {}",
+                    rel.id, id
+                ))
+            })
+        };
+        sqlx::query(
+            r#"INSERT INTO relationship (blueprintId, parentId, childId, parentPole, childPole, type)
+			VALUES (?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(blueprint_id)
+        .bind(node_id(&rel.parent_id)?)
+        .bind(node_id(&rel.child_id)?)
+        .bind(ParentPole::from(rel.parent_pole))
+        .bind(ChildPole::from(rel.child_pole))
+        .bind(Type::from(rel.r#type))
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(blueprint_id)
+}
+
+/// Get the id of the tag of a project with this title, create it if it doesn't exist
+async fn find_or_create_tag(
+    tx: &mut Transaction<'_, MySql>,
+    project_id: &i32,
+    title: &String,
+    color: &Option<String>,
+    primary: bool,
+    user_id: &String,
+) -> Result<i32, Status> {
+    let existing: Option<(i32,)> =
+        sqlx::query_as("SELECT id FROM tag WHERE projectId = ? AND title = ?")
+            .bind(project_id)
+            .bind(title)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+    if let Some((id,)) = existing {
+        return Ok(id);
+    }
+    let tag = sqlx::query(
+        r#"INSERT INTO tag (title, color, `primary`, projectId, createdById)
+		VALUES (?, ?, ?, ?, ?)"#,
+    )
+    .bind(title)
+    .bind(color)
+    .bind(primary)
+    .bind(project_id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(tag.last_insert_id() as i32)
+}
+
+pub async fn get_document_tags(doc_id: &i32) -> Result<Vec<TagModel>, Status> {
+    let tags = sqlx::query_as(
+        r#"SELECT tag.* FROM tag
+		JOIN document_tag ON document_tag.tagId = tag.id WHERE document_tag.documentId = ?"#,
+    )
+    .bind(doc_id)
+    .fetch_all(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(tags)
+}
+
+/// Copy a document with its sheets and tags to a project, with the given contents
+/// Tags are matched by title in the target project and created if they don't exist
+pub async fn duplicate_document(
+    doc: &DocumentModel,
+    title: &String,
+    content: &String,
+    sheets: &[(SheetModel, String)],
+    tags: &[TagModel],
+    project_id: &i32,
+    user_id: &String,
+) -> Result<i32, Status> {
+    let mut tx = POOL
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    let doc_id = sqlx::query(
+        r#"INSERT INTO document (title, content, color, projectId, createdById, lastEditorId, uid)
+		VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(title)
+    .bind(content)
+    .bind(&doc.color)
+    .bind(project_id)
+    .bind(user_id)
+    .bind(user_id)
+    .bind(Uuid::new_v4())
+    .execute(&mut tx)
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?
+    .last_insert_id() as i32;
+    for (sheet, content) in sheets {
+        sqlx::query(
+            r#"INSERT INTO sheet (title, content, color, projectId, documentId, createdById, lastEditorId, uid)
+			VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(&sheet.title)
+        .bind(content)
+        .bind(&sheet.color)
+        .bind(project_id)
+        .bind(doc_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(Uuid::new_v4())
+        .execute(&mut tx)
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    }
+    for tag in tags {
+        let tag_id = find_or_create_tag(
+            &mut tx,
+            project_id,
+            &tag.title,
+            &tag.color,
+            tag.primary != 0,
+            user_id,
+        )
+        .await?;
+        sqlx::query("INSERT IGNORE INTO document_tag (documentId, tagId) VALUES (?, ?)")
+            .bind(doc_id)
+            .bind(tag_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+    }
+    tx.commit()
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(doc_id)
+}
+
+/// Get the role of a user in a project, `None` if they're not a member
+pub async fn get_project_role(
+    project_id: &i32,
+    user_id: &str,
+) -> Result<Option<ProjectRole>, Status> {
+    let role: Option<(ProjectRole,)> =
+        sqlx::query_as("SELECT role FROM project_users_user WHERE projectId = ? AND userId = ?")
+            .bind(project_id)
+            .bind(user_id)
+            .fetch_optional(POOL.get().unwrap())
+            .await
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(role.map(|(role,)| role))
+}
+
+pub async fn get_document_project(id: &i32) -> Result<Option<i32>, Status> {
+    let project: Option<(i32,)> = sqlx::query_as("SELECT projectId FROM document WHERE id = ?")
+        .bind(id)
+        .fetch_optional(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(project.map(|(id,)| id))
+}
+
+pub async fn get_sheet_project(id: &i32) -> Result<Option<i32>, Status> {
+    let project: Option<(i32,)> = sqlx::query_as("SELECT projectId FROM sheet WHERE id = ?")
+        .bind(id)
+        .fetch_optional(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(project.map(|(id,)| id))
+}
+
+pub async fn get_blueprint_project(id: &i32) -> Result<Option<i32>, Status> {
+    let project: Option<(i32,)> = sqlx::query_as("SELECT projectId FROM blueprint WHERE id = ?")
+        .bind(id)
+        .fetch_optional(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(project.map(|(id,)| id))
+}
+
+pub async fn get_node_project(id: &i32) -> Result<Option<i32>, Status> {
+    let project: Option<(i32,)> = sqlx::query_as(
+        "SELECT blueprint.projectId FROM node JOIN blueprint ON blueprint.id = node.blueprintId WHERE node.id = ?",
+    )
+    .bind(id)
+    .fetch_optional(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(project.map(|(id,)| id))
+}
+
+/// Revoked token ids with the time they expire at, in seconds since the epoch
+pub async fn get_revoked_tokens() -> Result<Vec<(String, Option<i64>)>, Status> {
+    sqlx::query_as("SELECT jti, expiresAt FROM revoked_token")
+        .fetch_all(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))
+}
+
+/// Users whose tokens issued before a time, in seconds since the epoch, are revoked
+pub async fn get_revoked_users() -> Result<Vec<(String, i64)>, Status> {
+    sqlx::query_as("SELECT userId, issuedBefore FROM revoked_user_token")
+        .fetch_all(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))
+}
+
+/// Return false if the token was already revoked
+pub async fn revoke_token(jti: &str, expires_at: Option<i64>) -> Result<bool, Status> {
+    let res = sqlx::query("INSERT IGNORE INTO revoked_token (jti, expiresAt) VALUES (?, ?)")
+        .bind(jti)
+        .bind(expires_at)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn revoke_user_tokens(user_id: &str, issued_before: i64) -> Result<(), Status> {
+    sqlx::query(
+        "INSERT INTO revoked_user_token (userId, issuedBefore) VALUES (?, ?)
+        ON DUPLICATE KEY UPDATE issuedBefore = GREATEST(issuedBefore, VALUES(issuedBefore))",
+    )
+    .bind(user_id)
+    .bind(issued_before)
+    .execute(POOL.get().unwrap())
+    .await
+    .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Remove the revoked tokens that expired before `cutoff`, they are rejected by their expiry anyway
+pub async fn delete_expired_revoked_tokens(cutoff: i64) -> Result<(), Status> {
+    sqlx::query("DELETE FROM revoked_token WHERE expiresAt < ?")
+        .bind(cutoff)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
+
+/// Remove the user revocations of tokens issued before `cutoff`, every one of them expired since
+pub async fn delete_expired_revoked_users(cutoff: i64) -> Result<(), Status> {
+    sqlx::query("DELETE FROM revoked_user_token WHERE issuedBefore < ?")
+        .bind(cutoff)
+        .execute(POOL.get().unwrap())
+        .await
+        .map_err(|e| Status::data_loss(e.to_string()))?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Extractive summaries made of the most representative sentences of a text.
+//! Sentences are scored with the frequency of their words in the whole text,
+//! common words are ignored and the selected sentences keep their original order.
+use std::collections::{HashMap, HashSet};
+
+use lazy_static::lazy_static;
+
+/// Number of sentences of a summary when none is requested
+pub const DEFAULT_SENTENCES: usize = 3;
+
+lazy_static! {
+    static ref STOPWORDS: HashSet<&'static str> = [
+        // English
+        "a", "about", "after", "all", "also", "an", "and", "any", "are", "as", "at", "be", "because",
+        "been", "but", "by", "can", "could", "did", "do", "does", "for", "from", "had", "has", "have",
+        "he", "her", "him", "his", "how", "i", "if", "in", "into", "is", "it", "its", "just", "me",
+        "more", "my", "no", "not", "of", "on", "one", "or", "our", "out", "she", "so", "some", "than",
+        "that", "the", "their", "them", "then", "there", "these", "they", "this", "to", "up", "us",
+        "was", "we", "were", "what", "when", "which", "who", "will", "with", "would", "you", "your",
+        // French
+        "au", "aux", "avec", "ce", "ces", "cette", "dans", "de", "des", "du", "elle", "en", "est",
+        "et", "eux", "il", "ils", "je", "la", "le", "les", "leur", "lui", "ma", "mais", "mes",
+        "mon", "ne", "nous", "ou", "par", "pas", "pour", "qu", "que", "qui", "sa", "se", "ses",
+        "son", "sur", "ta", "te", "tes", "ton", "tu", "un", "une", "vous", "y",
+    ]
+    .into_iter()
+    .collect();
+}
+
+/// Number of sentences requested by a client, 0 for the default
+pub fn max_sentences(requested: u32) -> usize {
+    match requested {
+        0 => DEFAULT_SENTENCES,
+        n => n as usize,
+    }
+}
+
+/// Summarize a text with at most `max_sentences` of its sentences
+/// Markup tags are ignored so that rich text content can be summarized
+pub fn summarize(text: &str, max_sentences: usize) -> String {
+    let text = strip_markup(text);
+    let sentences = split_sentences(&text);
+    if sentences.len() <= max_sentences {
+        return sentences.join(" ");
+    }
+
+    let mut frequencies: HashMap<String, f64> = HashMap::new();
+    for word in sentences.iter().flat_map(|s| words(s)) {
+        *frequencies.entry(word).or_default() += 1.0;
+    }
+    let max_frequency = frequencies.values().copied().fold(1.0, f64::max);
+
+    // The mean normalized frequency of the words of a sentence, so that long sentences aren't favoured
+    let mut scored: Vec<(usize, f64)> = sentences
+        .iter()
+        .enumerate()
+        .map(|(index, sentence)| {
+            let words = words(sentence);
+            let score = if words.is_empty() {
+                0.0
+            } else {
+                words
+                    .iter()
+                    .map(|w| frequencies[w] / max_frequency)
+                    .sum::<f64>()
+                    / words.len() as f64
+            };
+            (index, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let mut selected: Vec<usize> = scored
+        .into_iter()
+        .take(max_sentences)
+        .map(|(index, _)| index)
+        .collect();
+    selected.sort();
+    selected
+        .into_iter()
+        .map(|index| sentences[index].as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Remove markup tags and decode the common entities
+fn strip_markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            // Tags often separate paragraphs, they are replaced by a line break
+            '>' if in_tag => {
+                in_tag = false;
+                out.push('\n');
+            }
+            _ if !in_tag => out.push(c),
+            _ => (),
+        }
+    }
+    out.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Split a text on sentence punctuation and on empty lines
+fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let paragraph_end = c == '\n' && !matches!(chars.peek(), Some(n) if *n != '\n');
+        if !paragraph_end {
+            current.push(if c.is_whitespace() { ' ' } else { c });
+        }
+        let sentence_end = matches!(c, '.' | '!' | '?' | '…')
+            && !matches!(chars.peek(), Some(n) if !n.is_whitespace());
+        if sentence_end || paragraph_end {
+            let sentence = current.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+            current.clear();
+        }
+    }
+    let sentence = current.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !sentence.is_empty() {
+        sentences.push(sentence);
+    }
+    sentences
+}
+
+/// Lowercase words of a sentence without the common ones
+fn words(sentence: &str) -> Vec<String> {
+    sentence
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .filter(|w| !STOPWORDS.contains(w.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORY: &str = "Dragons guard the castle. The weather was mild. \
+        Dragons attack the castle at night. Birds sing. The castle falls to the dragons.";
+
+    #[test]
+    fn most_frequent_sentences_are_kept_in_order() {
+        assert_eq!(
+            summarize(STORY, 2),
+            "Dragons guard the castle. The castle falls to the dragons."
+        );
+        assert_eq!(
+            summarize(STORY, 3),
+            "Dragons guard the castle. Dragons attack the castle at night. The castle falls to the dragons."
+        );
+        // Sentences with the same score are selected in the order of the text
+        assert_eq!(
+            summarize(STORY, 4),
+            "Dragons guard the castle. The weather was mild. Dragons attack the castle at night. The castle falls to the dragons."
+        );
+    }
+
+    #[test]
+    fn short_texts_are_kept_whole() {
+        assert_eq!(
+            summarize(STORY, 5),
+            STORY.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+        assert_eq!(summarize("", 3), "");
+        assert_eq!(max_sentences(0), DEFAULT_SENTENCES);
+        assert_eq!(max_sentences(1), 1);
+    }
+
+    #[test]
+    fn markup_and_paragraphs_split_sentences() {
+        assert_eq!(
+            summarize(
+                "<p>First idea here.</p><p>Second&nbsp;idea &amp; more</p>",
+                3
+            ),
+            "First idea here. Second idea & more"
+        );
+        assert_eq!(
+            split_sentences("Version 3.5 is out! Is it?\n\nNew paragraph\nsame sentence"),
+            vec![
+                "Version 3.5 is out!",
+                "Is it?",
+                "New paragraph same sentence"
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Positions in a text are counted in UTF-16 code units, like in the browser editor.
+//! Every conversion between positions and char indexes of a `Rope` is made here.
+use ropey::Rope;
+use tonic::Status;
+
+/// Length of a text in UTF-16 code units
+pub fn utf16_len(text: &str) -> i32 {
+    text.encode_utf16().count() as i32
+}
+
+/// Convert a UTF-16 position to a char index in a rope
+/// Return `None` if the position is out of bounds or splits a surrogate pair
+pub fn char_index(rope: &Rope, position: i32) -> Option<usize> {
+    if position < 0 || position as usize > rope.len_utf16_cu() {
+        return None;
+    }
+    let index = rope.utf16_cu_to_char(position as usize);
+    (rope.char_to_utf16_cu(index) == position as usize).then_some(index)
+}
+
+/// Insert `content` at a UTF-16 position
+pub fn insert(rope: &mut Rope, position: i32, content: &str) -> Result<(), Status> {
+    let index =
+        char_index(rope, position).ok_or(Status::invalid_argument("Invalid insert position"))?;
+    rope.insert(index, content);
+    Ok(())
+}
+
+/// Remove `size` UTF-16 code units from a UTF-16 position
+pub fn remove(rope: &mut Rope, position: i32, size: i32) -> Result<(), Status> {
+    let start = char_index(rope, position);
+    let end = position
+        .checked_add(size)
+        .and_then(|end| char_index(rope, end));
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => {
+            rope.remove(start..end);
+            Ok(())
+        }
+        _ => Err(Status::invalid_argument("Invalid remove range")),
+    }
+}
+
+/// Get `size` UTF-16 code units of a rope from a UTF-16 position
+pub fn slice(rope: &Rope, position: i32, size: i32) -> Result<String, Status> {
+    let start = char_index(rope, position);
+    let end = position
+        .checked_add(size)
+        .and_then(|end| char_index(rope, end));
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok(rope.slice(start..end).to_string()),
+        _ => Err(Status::invalid_argument("Invalid slice range")),
+    }
+}
+
+/// CRC32 of the UTF-8 encoded content of a rope
+pub fn crc(rope: &Rope) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for chunk in rope.chunks() {
+        hasher.update(chunk.as_bytes());
+    }
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_counted_in_utf16_code_units() {
+        // "é" is one code unit and "😀" is a surrogate pair of two
+        let mut rope = Rope::from_str("é😀");
+        assert_eq!(utf16_len("é😀"), 3);
+        insert(&mut rope, 3, "!").unwrap();
+        assert_eq!(rope.to_string(), "é😀!");
+        assert_eq!(slice(&rope, 1, 2).unwrap(), "😀");
+        remove(&mut rope, 0, 1).unwrap();
+        assert_eq!(rope.to_string(), "😀!");
+    }
+
+    #[test]
+    fn positions_splitting_a_surrogate_pair_are_rejected() {
+        let mut rope = Rope::from_str("é😀");
+        assert!(insert(&mut rope, 2, "!").is_err());
+        assert!(remove(&mut rope, 1, 1).is_err());
+        assert!(remove(&mut rope, 2, 1).is_err());
+        assert!(slice(&rope, 1, 1).is_err());
+        assert_eq!(rope.to_string(), "é😀");
+    }
+
+    #[test]
+    fn ranges_that_overflow_are_rejected() {
+        let mut rope = Rope::from_str("abc");
+        assert!(remove(&mut rope, 1, i32::MAX).is_err());
+        assert!(slice(&rope, 1, i32::MAX).is_err());
+    }
+}
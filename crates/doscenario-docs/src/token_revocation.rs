@@ -0,0 +1,169 @@
+//! Revoked JWTs, rejected on every call even though their signature and claims are valid.
+//! A single token is revoked by its `jti` and every token of a user is revoked by the time they were issued at.
+//! A user revocation lasts `TOKEN_MAX_LIFETIME` seconds, after which every token it covers expired.
+//! Revocations are stored in the database and reloaded every `REVOCATION_REFRESH` seconds
+//! so that they apply to every instance of the services.
+use std::time::Duration;
+
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use tonic::Status;
+
+use crate::{jwt_keys::JWT_KEYS, queries, utils::now_secs};
+
+pub static REVOKED_TOKENS: OnceCell<RevokedTokens> = OnceCell::new();
+
+pub async fn load_revoked_tokens() {
+    let revoked = RevokedTokens::from_env();
+    revoked
+        .reload()
+        .await
+        .expect("Failed to load revoked tokens");
+    REVOKED_TOKENS
+        .set(revoked)
+        .expect("Failed to set revoked tokens");
+}
+
+/// Reload the revocations every `REVOCATION_REFRESH` seconds, 30 by default
+/// Revoked tokens that can't be accepted anymore are removed from the database on the way
+pub async fn refresh_revoked_tokens() {
+    let refresh = std::env::var("REVOCATION_REFRESH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    let delay = Duration::from_secs(refresh);
+    loop {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = queries::delete_expired_revoked_tokens(expiry_cutoff()).await {
+            log::error!("Error removing expired revoked tokens: {:?}", e);
+        }
+        if let Some(revoked) = REVOKED_TOKENS.get() {
+            if let Err(e) = queries::delete_expired_revoked_users(revoked.user_cutoff()).await {
+                log::error!("Error removing expired user revocations: {:?}", e);
+            }
+            if let Err(e) = revoked.reload().await {
+                log::error!("Error reloading revoked tokens: {:?}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RevokedTokens {
+    // Map a revoked jti to the time the token expires at
+    tokens: DashMap<String, Option<i64>>,
+    // Map a user to the time before which their tokens are revoked
+    users: DashMap<String, i64>,
+    // Longest lifetime of an accepted token in seconds
+    max_lifetime: i64,
+}
+
+impl RevokedTokens {
+    pub fn from_env() -> Self {
+        Self {
+            tokens: DashMap::new(),
+            users: DashMap::new(),
+            max_lifetime: token_max_lifetime(),
+        }
+    }
+
+    /// Check if a token is revoked from its `sub`, `jti` and `iat` claims
+    /// Tokens without `iat` of a revoked user are rejected as they can't be told apart from the old ones,
+    /// until the revocation expires
+    pub fn is_revoked(&self, user_id: &str, jti: Option<&str>, iat: Option<i64>) -> bool {
+        if jti.is_some_and(|jti| self.tokens.contains_key(jti)) {
+            return true;
+        }
+        match self.users.get(user_id) {
+            Some(issued_before) => !matches!(iat, Some(iat) if iat > *issued_before),
+            None => false,
+        }
+    }
+
+    /// Revoke a single token, it can be forgotten once it expired
+    /// Return false if it was already revoked, by this instance or another one
+    pub async fn revoke_token(&self, jti: &str, expires_at: Option<i64>) -> Result<bool, Status> {
+        let revoked = queries::revoke_token(jti, expires_at).await?;
+        self.tokens.insert(jti.to_string(), expires_at);
+        Ok(revoked)
+    }
+
+    /// Revoke every token of a user issued until now
+    pub async fn revoke_user(&self, user_id: &str) -> Result<(), Status> {
+        let issued_before = now_secs();
+        queries::revoke_user_tokens(user_id, issued_before).await?;
+        self.users
+            .entry(user_id.to_string())
+            .and_modify(|before| *before = issued_before.max(*before))
+            .or_insert(issued_before);
+        Ok(())
+    }
+
+    /// Add the revocations of the database and forget the expired tokens
+    /// Revocations are never removed otherwise, so a token revoked by this instance stays revoked
+    /// even if it was revoked after the database was read
+    async fn reload(&self) -> Result<(), Status> {
+        let (tokens, users) =
+            tokio::try_join!(queries::get_revoked_tokens(), queries::get_revoked_users())?;
+        for (jti, expires_at) in tokens {
+            self.tokens.insert(jti, expires_at);
+        }
+        for (user_id, issued_before) in users {
+            self.users
+                .entry(user_id)
+                .and_modify(|before| *before = issued_before.max(*before))
+                .or_insert(issued_before);
+        }
+        let cutoff = expiry_cutoff();
+        self.tokens
+            .retain(|_, expires_at| !matches!(expires_at, Some(exp) if *exp < cutoff));
+        let cutoff = self.user_cutoff();
+        self.users
+            .retain(|_, issued_before| *issued_before >= cutoff);
+        Ok(())
+    }
+
+    /// User revocations of tokens issued before this time only cover expired tokens
+    fn user_cutoff(&self) -> i64 {
+        expiry_cutoff() - self.max_lifetime
+    }
+}
+
+/// Tokens last at most `TOKEN_MAX_LIFETIME` seconds, 30 days by default like the refresh tokens
+pub fn token_max_lifetime() -> i64 {
+    std::env::var("TOKEN_MAX_LIFETIME")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30 * 24 * 60 * 60)
+}
+
+/// Tokens that expired before this time are rejected whatever the leeway on `exp`
+fn expiry_cutoff() -> i64 {
+    let leeway = JWT_KEYS.get().map(|keys| keys.leeway()).unwrap_or_default();
+    now_secs() - leeway as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_revocation_rejects_older_tokens_and_tokens_without_iat() {
+        let revoked = RevokedTokens::from_env();
+        revoked.users.insert("user".to_string(), 100);
+        assert!(revoked.is_revoked("user", None, Some(50)));
+        assert!(revoked.is_revoked("user", None, Some(100)));
+        assert!(revoked.is_revoked("user", None, None));
+        assert!(!revoked.is_revoked("user", None, Some(150)));
+        assert!(!revoked.is_revoked("other", None, None));
+    }
+
+    #[test]
+    fn token_revocation_only_rejects_its_jti() {
+        let revoked = RevokedTokens::from_env();
+        revoked.tokens.insert("jti".to_string(), None);
+        assert!(revoked.is_revoked("user", Some("jti"), Some(100)));
+        assert!(!revoked.is_revoked("user", Some("other"), Some(100)));
+        assert!(!revoked.is_revoked("user", None, Some(100)));
+    }
+}
@@ -1,24 +1,10 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use tokio::sync::Mutex;
 use tonic::Request;
 
 use crate::UserId;
 
-pub trait RemoveRange {
-    fn to_remove_range(&mut self, start: usize, stop: usize) -> Self;
-}
-
-impl RemoveRange for String {
-    fn to_remove_range(&mut self, start: usize, stop: usize) -> Self {
-        let mut rslt = "".to_string();
-        for (i, c) in self.chars().enumerate() {
-            if start > i || stop < i + 1 {
-                rslt.push(c);
-            }
-        }
-        rslt
-    }
-}
-
 pub async fn get_snowflake() -> i64 {
     use lazy_static::lazy_static;
     use snowflake::SnowflakeIdGenerator;
@@ -29,8 +15,15 @@ pub async fn get_snowflake() -> i64 {
     ID_GENERATOR.lock().await.real_time_generate()
 }
 
+/// Current time in seconds since the epoch, the unit of the `iat` and `exp` claims
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
 
 pub fn unpack_req<T>(req: Request<T>) -> (T, UserId) {
-	let user_id = req.extensions().get::<UserId>().unwrap().clone();
-	(req.into_inner(), user_id)
-}
\ No newline at end of file
+    let user_id = req.extensions().get::<UserId>().unwrap().clone();
+    (req.into_inner(), user_id)
+}
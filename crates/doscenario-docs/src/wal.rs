@@ -0,0 +1,188 @@
+//! Write-ahead log of the changes applied to cached documents.
+//! Each document has its own append-only segment of length delimited records,
+//! the segment is truncated once the document content is saved to the database.
+//! The saved content is stored with the epoch and change id of the last record it includes,
+//! so that records left in a segment by a crash during a save are not applied twice.
+//! Segments are written by a dedicated thread in the order the operations are queued,
+//! so that the cache locks are not held during disk writes.
+use std::{
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use prost::Message;
+use tokio::sync::oneshot;
+use tonic::Status;
+
+use crate::{docs::Change, docs_cache::DocKey};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WalRecord {
+    #[prost(int64, tag = "1")]
+    pub session: i64,
+    #[prost(uint64, tag = "2")]
+    pub change_id: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub changes: Vec<Change>,
+    // Load of the text in the cache, change ids start over on each load
+    #[prost(int64, tag = "4")]
+    pub epoch: i64,
+}
+
+enum WalOp {
+    Append(DocKey, WalRecord),
+    Truncate(DocKey, Vec<WalRecord>),
+}
+
+type WalReply = oneshot::Sender<Result<(), Status>>;
+
+#[derive(Debug, Clone)]
+pub struct Wal {
+    dir: PathBuf,
+    writer: mpsc::Sender<(WalOp, WalReply)>,
+}
+
+impl Wal {
+    /// Open the write-ahead log stored in the `WAL_DIR` directory, `./wal` by default
+    pub fn from_env() -> Self {
+        let dir = PathBuf::from(std::env::var("WAL_DIR").unwrap_or_else(|_| "wal".to_string()));
+        fs::create_dir_all(&dir).expect("Failed to create WAL directory");
+        let (writer, ops) = mpsc::channel::<(WalOp, WalReply)>();
+        let writer_dir = dir.clone();
+        thread::Builder::new()
+            .name("wal-writer".to_string())
+            .spawn(move || {
+                for (op, reply) in ops {
+                    let res = match op {
+                        WalOp::Append(key, record) => append_record(&writer_dir, key, &record),
+                        WalOp::Truncate(key, records) => {
+                            replace_segment(&writer_dir, key, &records)
+                        }
+                    };
+                    let _ = reply.send(res);
+                }
+            })
+            .expect("Failed to start WAL writer");
+        Self { dir, writer }
+    }
+
+    /// Queue an operation to the writer thread
+    /// The returned future resolves once the operation is done
+    fn queue(&self, op: WalOp) -> impl Future<Output = Result<(), Status>> {
+        let (reply, done) = oneshot::channel();
+        let queued = self.writer.send((op, reply)).is_ok();
+        async move {
+            if !queued {
+                return Err(Status::internal("WAL writer stopped"));
+            }
+            done.await
+                .unwrap_or_else(|_| Err(Status::internal("WAL writer stopped")))
+        }
+    }
+
+    /// Queue a record to append to the segment of a document
+    /// The returned future resolves once the record is on disk
+    pub fn append(
+        &self,
+        key: DocKey,
+        record: WalRecord,
+    ) -> impl Future<Output = Result<(), Status>> {
+        self.queue(WalOp::Append(key, record))
+    }
+
+    /// Queue the replacement of the segment of a document with the records not saved yet
+    /// The segment is removed if there is none
+    pub fn truncate(
+        &self,
+        key: DocKey,
+        records: Vec<WalRecord>,
+    ) -> impl Future<Output = Result<(), Status>> {
+        self.queue(WalOp::Truncate(key, records))
+    }
+
+    /// Read the records of every segment left on disk
+    /// A record partially written before a crash ends its segment
+    pub fn read_segments(&self) -> Result<Vec<(DocKey, Vec<WalRecord>)>, Status> {
+        let mut segments = Vec::new();
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| Status::internal(format!("Cannot read WAL directory: {e}")))?;
+        for entry in entries.flatten() {
+            let Some(key) = entry.file_name().to_str().and_then(segment_key) else {
+                continue;
+            };
+            let data = fs::read(entry.path())
+                .map_err(|e| Status::internal(format!("Cannot read WAL of {key:?}: {e}")))?;
+            let mut buf = data.as_slice();
+            let mut records = Vec::new();
+            while !buf.is_empty() {
+                match WalRecord::decode_length_delimited(&mut buf) {
+                    Ok(record) => records.push(record),
+                    Err(e) => {
+                        log::warn!("Ignoring partial WAL record of {:?}: {}", key, e);
+                        break;
+                    }
+                }
+            }
+            segments.push((key, records));
+        }
+        Ok(segments)
+    }
+}
+
+fn segment_path(dir: &Path, key: DocKey) -> PathBuf {
+    dir.join(format!("{}.wal", key.name()))
+}
+
+fn segment_key(name: &str) -> Option<DocKey> {
+    let (kind, id) = name.strip_suffix(".wal")?.split_once('-')?;
+    let id = id.parse().ok()?;
+    match kind {
+        "doc" => Some(DocKey::Doc(id)),
+        "sheet" => Some(DocKey::Sheet(id)),
+        "node_content" => Some(DocKey::NodeContent(id)),
+        "node_summary" => Some(DocKey::NodeSummary(id)),
+        _ => None,
+    }
+}
+
+/// Append a record to the segment of a document and wait for it to be on disk
+fn append_record(dir: &Path, key: DocKey, record: &WalRecord) -> Result<(), Status> {
+    let write = || -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, key))?;
+        file.write_all(&record.encode_length_delimited_to_vec())?;
+        file.sync_data()
+    };
+    write().map_err(|e| Status::internal(format!("Cannot write WAL of {key:?}: {e}")))
+}
+
+/// Replace the segment of a document with the given records, or remove it if there is none
+/// The new segment is synced before the rename and the directory after,
+/// so that a crash leaves either the old or the new segment
+fn replace_segment(dir: &Path, key: DocKey, records: &[WalRecord]) -> Result<(), Status> {
+    let path = segment_path(dir, key);
+    let write = || -> std::io::Result<()> {
+        if records.is_empty() {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                res => res?,
+            }
+        } else {
+            let tmp_path = path.with_extension("wal.tmp");
+            let mut file = File::create(&tmp_path)?;
+            for record in records {
+                file.write_all(&record.encode_length_delimited_to_vec())?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+        File::open(dir)?.sync_all()
+    };
+    write().map_err(|e| Status::internal(format!("Cannot truncate WAL of {key:?}: {e}")))
+}
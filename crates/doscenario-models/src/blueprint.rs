@@ -2,6 +2,7 @@ use sqlx::types::time::PrimitiveDateTime;
 use sqlx::FromRow;
 
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
 pub struct BlueprintModel {
     
     pub id: i32,
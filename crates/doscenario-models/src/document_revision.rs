@@ -0,0 +1,18 @@
+use sqlx::types::time::PrimitiveDateTime;
+use sqlx::FromRow;
+
+#[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct DocumentRevisionModel {
+    
+    pub id: i32,
+    
+    pub document_id: i32,
+    
+    pub author_id: Option<String>,
+    
+	#[sqlx(default)]
+    pub content: Option<String>,
+    
+    pub created_date: PrimitiveDateTime,
+}
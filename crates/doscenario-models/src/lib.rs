@@ -1,6 +1,7 @@
 pub mod blueprint;
 pub mod blueprint_tag;
 pub mod document;
+pub mod document_revision;
 pub mod document_tag;
 pub mod file;
 pub mod files_tag;
@@ -2,6 +2,7 @@ use sqlx::types::time::PrimitiveDateTime;
 use sqlx::FromRow;
 
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
 pub struct NodeModel {
     
     pub id: i32,
@@ -17,7 +18,6 @@ pub struct NodeModel {
     pub last_editor_id: Option<String>,
     pub x: i32,
     pub y: i32,
-    pub locked: i8,
     
     pub summary: Option<String>,
     
@@ -1,6 +1,7 @@
 use sqlx::FromRow;
 
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
 pub struct NodeTagModel {
     
     pub node_id: i32,
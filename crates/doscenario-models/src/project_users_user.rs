@@ -1,8 +1,12 @@
+use crate::sea_orm_active_enums::ProjectRole;
 use sqlx::FromRow;
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
 pub struct ProjectUsersUserModel {
     
     pub project_id: i32,
     
     pub user_id: String,
-}
\ No newline at end of file
+
+    pub role: ProjectRole,
+}
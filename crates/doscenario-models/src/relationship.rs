@@ -3,6 +3,7 @@ use sqlx::FromRow;
 
 
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
+#[sqlx(rename_all = "camelCase")]
 pub struct RelationshipModel {
     
     pub id: i32,
@@ -16,5 +17,6 @@ pub struct RelationshipModel {
     pub parent_pole: ParentPole,
     
     pub child_pole: ChildPole,
+    #[sqlx(rename = "type")]
     pub r#type: Type,
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::Type)]
 
 pub enum ChildPole {
     
@@ -10,7 +10,7 @@ pub enum ChildPole {
     
     W,
 }
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::Type)]
 
 pub enum ParentPole {
     
@@ -22,7 +22,7 @@ pub enum ParentPole {
     
     W,
 }
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::Type)]
 
 pub enum Type {
     
@@ -30,3 +30,12 @@ pub enum Type {
     
     Loopback,
 }
+/// Role of a user in a project, ordered from the least to the most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum ProjectRole {
+    Viewer,
+    Commenter,
+    Editor,
+    Owner,
+}
@@ -2,7 +2,7 @@ use sqlx::FromRow;
 
 
 #[derive(Clone, Debug, PartialEq, Eq, FromRow)]
-
+#[sqlx(rename_all = "camelCase")]
 pub struct TagModel {
     
     pub id: i32,
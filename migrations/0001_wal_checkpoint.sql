@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS `wal_checkpoint` (
+	`textKey` varchar(64) NOT NULL,
+	`epoch` bigint NOT NULL,
+	`changeId` bigint unsigned NOT NULL,
+	PRIMARY KEY (`textKey`)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS `document_revision` (
+	`id` int NOT NULL AUTO_INCREMENT,
+	`documentId` int NOT NULL,
+	`authorId` varchar(36) NULL,
+	`content` longtext NOT NULL,
+	`createdDate` datetime(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+	PRIMARY KEY (`id`),
+	KEY `IDX_document_revision_documentId` (`documentId`),
+	CONSTRAINT `FK_document_revision_documentId` FOREIGN KEY (`documentId`) REFERENCES `document` (`id`) ON DELETE CASCADE,
+	CONSTRAINT `FK_document_revision_authorId` FOREIGN KEY (`authorId`) REFERENCES `user` (`id`) ON DELETE SET NULL
+);
@@ -0,0 +1,7 @@
+ALTER TABLE `project_users_user`
+	ADD COLUMN `role` enum('owner', 'editor', 'commenter', 'viewer') NOT NULL DEFAULT 'editor';
+
+UPDATE `project_users_user`
+	JOIN `project` ON `project`.`id` = `project_users_user`.`projectId`
+	SET `project_users_user`.`role` = 'owner'
+	WHERE `project`.`createdById` = `project_users_user`.`userId`;
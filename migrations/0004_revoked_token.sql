@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS `revoked_token` (
+	`jti` varchar(255) NOT NULL,
+	`expiresAt` bigint NULL,
+	`createdDate` datetime(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+	PRIMARY KEY (`jti`)
+);
+
+CREATE TABLE IF NOT EXISTS `revoked_user_token` (
+	`userId` varchar(36) NOT NULL,
+	`issuedBefore` bigint NOT NULL,
+	`createdDate` datetime(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+	PRIMARY KEY (`userId`),
+	CONSTRAINT `FK_revoked_user_token_userId` FOREIGN KEY (`userId`) REFERENCES `user` (`id`) ON DELETE CASCADE
+);